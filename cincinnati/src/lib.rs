@@ -23,20 +23,25 @@ extern crate serde_derive;
 use daggy::petgraph::visit::{IntoNodeReferences, NodeRef};
 use daggy::{Dag, Walker};
 use failure::Error;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::hash::Hash;
 
 pub const CONTENT_TYPE_GRAPH_V1: &str = "application/vnd.redhat.cincinnati.graph+json; version=1.0";
+pub const CONTENT_TYPE_GRAPH_V2: &str = "application/vnd.redhat.cincinnati.graph+json; version=2.0";
 
 #[derive(Debug, Default)]
 pub struct Graph {
     dag: Dag<Release, Empty>,
+    conditional_edges: Vec<ConditionalEdge>,
+    /// Index of the releases by version, kept in sync with the DAG.
+    versions: HashMap<Version, ReleaseId>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Release {
     Concrete(ConcreteRelease),
@@ -52,18 +57,95 @@ impl Release {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ConcreteRelease {
     pub version: Version,
     pub payload: String,
+    #[serde(serialize_with = "serialize_sorted")]
     pub metadata: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Serializes the given map with its keys in order, so that releases (and therefore graphs) are
+/// always serialized identically.
+fn serialize_sorted<S>(map: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AbstractRelease {
     pub version: Version,
 }
 
+/// A set of updates which are only recommended when none of the associated risks apply to the
+/// client. These are only part of the v2 schema.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ConditionalEdge {
+    pub edges: Vec<ConditionalUpdate>,
+    pub risks: Vec<Risk>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ConditionalUpdate {
+    pub from: Version,
+    pub to: Version,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Risk {
+    pub url: String,
+    pub name: String,
+    pub message: String,
+    #[serde(rename = "matchingRules")]
+    pub matching_rules: Vec<MatchingRule>,
+}
+
+/// Rule with which a client determines whether a risk applies to it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum MatchingRule {
+    Always,
+    PromQL { promql: PromQLQuery },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PromQLQuery {
+    pub promql: String,
+}
+
+/// Summary of the contents of a graph.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GraphStats {
+    pub releases: usize,
+    pub transitions: usize,
+    pub oldest: Option<Version>,
+    pub newest: Option<Version>,
+    /// Number of releases without any transitions to or from them
+    pub orphans: usize,
+}
+
+/// Changes between two graphs, with releases identified by their version.
+#[derive(Debug, Default, PartialEq)]
+pub struct GraphDiff {
+    pub added_releases: Vec<Version>,
+    pub removed_releases: Vec<Version>,
+    pub added_transitions: Vec<(Version, Version)>,
+    pub removed_transitions: Vec<(Version, Version)>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_releases.is_empty()
+            && self.removed_releases.is_empty()
+            && self.added_transitions.is_empty()
+            && self.removed_transitions.is_empty()
+    }
+}
+
+/// Identifies a release within a graph. IDs remain valid until a release is removed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct ReleaseId(daggy::NodeIndex);
 
 pub struct NextReleases<'a> {
@@ -102,20 +184,445 @@ impl Graph {
                 *node = release;
                 Ok(id)
             }
-            None => Ok(ReleaseId(self.dag.add_node(release))),
+            None => Ok(self.insert(release)),
         }
     }
 
+    fn insert(&mut self, release: Release) -> ReleaseId {
+        let version = release.version().clone();
+        let id = ReleaseId(self.dag.add_node(release));
+        self.versions.insert(version, id);
+        id
+    }
+
+    /// Adds an update from the source release to the target release. Updates which would create
+    /// a cycle (e.g. 1.0.0 -> 2.0.0 -> 1.0.0) are rejected.
     pub fn add_transition(&mut self, source: &ReleaseId, target: &ReleaseId) -> Result<(), Error> {
-        self.dag.add_edge(source.0, target.0, Empty {})?;
+        if self.dag.add_edge(source.0, target.0, Empty {}).is_err() {
+            return Err(self.cycle_error(source, target));
+        }
+        Ok(())
+    }
+
+    /// Adds all of the given updates at once. The graph is only checked for cycles once, rather
+    /// than once per update, so this is much faster than adding many updates one at a time. If
+    /// the updates would create a cycle, none of them are added.
+    pub fn add_transitions<I>(&mut self, transitions: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (ReleaseId, ReleaseId)>,
+    {
+        let transitions: Vec<_> = transitions.into_iter().collect();
+        if self.dag
+            .add_edges(
+                transitions
+                    .iter()
+                    .map(|(source, target)| (source.0, target.0, Empty {})),
+            )
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        // The updates are added again one at a time (and then removed) to find the one which
+        // closes the cycle, so that it can be named.
+        let mut added = Vec::new();
+        let mut closing = None;
+        for (source, target) in &transitions {
+            match self.dag.add_edge(source.0, target.0, Empty {}) {
+                Ok(edge) => added.push(edge),
+                Err(_) => {
+                    closing = Some((*source, *target));
+                    break;
+                }
+            }
+        }
+        for edge in added.into_iter().rev() {
+            self.dag.remove_edge(edge);
+        }
+        match closing {
+            Some((source, target)) => Err(self.cycle_error(&source, &target)),
+            None => bail!("Transitions would create a cycle"),
+        }
+    }
+
+    fn cycle_error(&self, source: &ReleaseId, target: &ReleaseId) -> Error {
+        format_err!(
+            "Transition from {} to {} would create a cycle",
+            self.dag[source.0].version(),
+            self.dag[target.0].version()
+        )
+    }
+
+    /// Removes the given release, along with all of its transitions, and returns it. The most
+    /// recently added release takes the place of the removed one, so any other IDs held by the
+    /// caller must be looked up again.
+    pub fn remove_release(&mut self, id: ReleaseId) -> Result<Release, Error> {
+        let release = self.dag
+            .remove_node(id.0)
+            .ok_or_else(|| format_err!("Release to remove is not part of the graph"))?;
+
+        // The last release is moved into the place of the removed one.
+        let version = release.version();
+        self.versions.remove(version);
+        if let Some(moved) = self.dag.node_weight(id.0) {
+            self.versions.insert(moved.version().clone(), id);
+        }
+
+        for edge in &mut self.conditional_edges {
+            edge.edges
+                .retain(|update| &update.from != version && &update.to != version);
+        }
+        self.conditional_edges.retain(|edge| !edge.edges.is_empty());
+
+        Ok(release)
+    }
+
+    /// Removes the update from the source release to the target release.
+    pub fn remove_transition(
+        &mut self,
+        source: &ReleaseId,
+        target: &ReleaseId,
+    ) -> Result<(), Error> {
+        match self.dag.find_edge(source.0, target.0) {
+            Some(edge) => {
+                self.dag.remove_edge(edge);
+                Ok(())
+            }
+            None => bail!(
+                "Transition from {} to {} is not part of the graph",
+                self.dag[source.0].version(),
+                self.dag[target.0].version()
+            ),
+        }
+    }
+
+    /// Adds an update which is only recommended when none of the given risks apply. Updates with
+    /// the same risks are grouped together.
+    pub fn add_conditional_transition(
+        &mut self,
+        source: &ReleaseId,
+        target: &ReleaseId,
+        risks: Vec<Risk>,
+    ) -> Result<(), Error> {
+        let update = match (self.dag.node_weight(source.0), self.dag.node_weight(target.0)) {
+            (Some(source), Some(target)) => ConditionalUpdate {
+                from: source.version().clone(),
+                to: target.version().clone(),
+            },
+            _ => bail!("Conditional update refers to an unknown release"),
+        };
+
+        match self.conditional_edges
+            .iter_mut()
+            .find(|edge| edge.risks == risks)
+        {
+            Some(edge) => edge.edges.push(update),
+            None => self.conditional_edges.push(ConditionalEdge {
+                edges: vec![update],
+                risks,
+            }),
+        }
         Ok(())
     }
 
+    pub fn conditional_edges(&self) -> &[ConditionalEdge] {
+        &self.conditional_edges
+    }
+
+    /// Returns a view of the graph which serializes to the v2 schema, including the conditional
+    /// edges.
+    pub fn v2(&self) -> GraphV2 {
+        GraphV2(self)
+    }
+
     pub fn find_by_version(&self, version: &Version) -> Option<ReleaseId> {
-        self.dag
+        self.versions.get(version).cloned()
+    }
+
+    /// Lists the releases whose versions match the given requirement (e.g. `>=4.1.0, <4.2.0`),
+    /// sorted by version.
+    pub fn releases_in_range(&self, requirement: &VersionReq) -> Vec<ReleaseId> {
+        let ids = self.dag
+            .node_references()
+            .filter(|(_, release)| requirement.matches(release.version()))
+            .map(|(id, _)| id)
+            .collect();
+        self.sorted_ids(ids)
+    }
+
+    pub fn release(&self, id: &ReleaseId) -> Option<&Release> {
+        self.dag.node_weight(id.0)
+    }
+
+    /// Returns the metadata of the given release, so that it can be modified. Abstract releases
+    /// have no metadata.
+    pub fn metadata_mut(&mut self, id: &ReleaseId) -> Option<&mut HashMap<String, String>> {
+        match self.dag.node_weight_mut(id.0) {
+            Some(Release::Concrete(release)) => Some(&mut release.metadata),
+            _ => None,
+        }
+    }
+
+    /// Finds the shortest sequence of updates from one version to another, returning the releases
+    /// along the way (including the first and last).
+    pub fn shortest_path(&self, from: &Version, to: &Version) -> Option<Vec<ReleaseId>> {
+        let source = self.find_by_version(from)?.0;
+        let target = self.find_by_version(to)?.0;
+
+        let mut previous = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(source);
+        while let Some(node) = queue.pop_front() {
+            if node == target {
+                let mut path = vec![ReleaseId(node)];
+                let mut node = node;
+                while node != source {
+                    node = previous[&node];
+                    path.push(ReleaseId(node));
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for next in self.dag.graph().neighbors(node) {
+                if next != source && !previous.contains_key(&next) {
+                    previous.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    /// Lists the releases to which the given version can eventually be updated, sorted by
+    /// version. Returns `None` if the version isn't part of the graph.
+    pub fn reachable_from(&self, version: &Version) -> Option<Vec<ReleaseId>> {
+        let source = self.find_by_version(version)?.0;
+
+        let mut reachable = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(source);
+        while let Some(node) = queue.pop_front() {
+            for next in self.dag.graph().neighbors(node) {
+                if reachable.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        Some(self.sorted_ids(reachable))
+    }
+
+    /// Lists the releases, other than the newest one, from which no updates are available, sorted
+    /// by version. Clusters running these releases are stranded.
+    pub fn dead_ends(&self) -> Vec<ReleaseId> {
+        let newest = self.releases().map(Release::version).max();
+        let dead_ends = self.dag
             .node_references()
-            .find(|nr| nr.weight().version() == version)
-            .map(|nr| ReleaseId(nr.id()))
+            .filter(|(id, release)| {
+                Some(release.version()) != newest
+                    && self.dag.graph().neighbors(*id).next().is_none()
+            })
+            .map(|(id, _)| id)
+            .collect();
+        self.sorted_ids(dead_ends)
+    }
+
+    fn sorted_ids(&self, ids: HashSet<daggy::NodeIndex>) -> Vec<ReleaseId> {
+        let mut ids: Vec<_> = ids.into_iter().collect();
+        ids.sort_by(|a, b| self.dag[*a].version().cmp(self.dag[*b].version()));
+        ids.into_iter().map(ReleaseId).collect()
+    }
+
+    pub fn releases(&self) -> impl Iterator<Item = &Release> {
+        self.dag.raw_nodes().iter().map(|node| &node.weight)
+    }
+
+    /// Iterates over the IDs of all of the releases, in the same order as `releases`.
+    pub fn release_ids(&self) -> impl Iterator<Item = ReleaseId> + '_ {
+        self.dag.node_references().map(|(id, _)| ReleaseId(id))
+    }
+
+    /// Iterates over all of the transitions, as pairs of source and target release IDs.
+    pub fn transitions(&self) -> impl Iterator<Item = (ReleaseId, ReleaseId)> + '_ {
+        self.dag
+            .raw_edges()
+            .iter()
+            .map(|edge| (ReleaseId(edge.source()), ReleaseId(edge.target())))
+    }
+
+    pub fn release_count(&self) -> usize {
+        self.dag.node_count()
+    }
+
+    pub fn transition_count(&self) -> usize {
+        self.dag.edge_count()
+    }
+
+    /// Returns a copy of the graph containing only the releases matching the predicate, along
+    /// with the transitions between them.
+    pub fn filter<F>(&self, mut predicate: F) -> Graph
+    where
+        F: FnMut(&Release) -> bool,
+    {
+        let mut graph = Graph::default();
+        let mut ids = HashMap::new();
+        for nr in self.dag.node_references() {
+            if predicate(nr.weight()) {
+                ids.insert(nr.id(), graph.insert(nr.weight().clone()).0);
+            }
+        }
+        for edge in self.dag.raw_edges() {
+            if let (Some(source), Some(target)) = (ids.get(&edge.source()), ids.get(&edge.target()))
+            {
+                graph
+                    .dag
+                    .add_edge(*source, *target, Empty {})
+                    .expect("a subgraph of a DAG cannot contain cycles");
+            }
+        }
+        for edge in &self.conditional_edges {
+            let edges: Vec<_> = edge.edges
+                .iter()
+                .filter(|update| {
+                    graph.find_by_version(&update.from).is_some()
+                        && graph.find_by_version(&update.to).is_some()
+                })
+                .cloned()
+                .collect();
+            if !edges.is_empty() {
+                graph.conditional_edges.push(ConditionalEdge {
+                    edges,
+                    risks: edge.risks.clone(),
+                });
+            }
+        }
+        graph
+    }
+
+    /// Combines the releases and transitions of both graphs, identifying releases by their
+    /// version. A concrete release takes precedence over an abstract release with the same
+    /// version. Concrete releases with the same version must have the same payload, and their
+    /// metadata is combined; conflicting metadata values are rejected.
+    pub fn merge(mut self, other: Graph) -> Result<Graph, Error> {
+        let mut ids = HashMap::new();
+        for nr in other.dag.node_references() {
+            let release = nr.weight().clone();
+            let id = match self.find_by_version(release.version()) {
+                Some(id) => {
+                    self.merge_release(&id, release)?;
+                    id
+                }
+                None => self.add_release(release)?,
+            };
+            ids.insert(nr.id(), id);
+        }
+
+        for edge in other.dag.raw_edges() {
+            let (source, target) = (&ids[&edge.source()], &ids[&edge.target()]);
+            if self.dag.find_edge(source.0, target.0).is_none() {
+                self.add_transition(source, target)?;
+            }
+        }
+
+        for edge in other.conditional_edges {
+            match self.conditional_edges
+                .iter_mut()
+                .find(|existing| existing.risks == edge.risks)
+            {
+                Some(existing) => for update in edge.edges {
+                    if !existing.edges.contains(&update) {
+                        existing.edges.push(update);
+                    }
+                },
+                None => self.conditional_edges.push(edge),
+            }
+        }
+
+        Ok(self)
+    }
+
+    fn merge_release(&mut self, id: &ReleaseId, release: Release) -> Result<(), Error> {
+        let node = &mut self.dag[id.0];
+        let release = match release {
+            Release::Concrete(release) => release,
+            Release::Abstract(_) => return Ok(()),
+        };
+        let existing = match node {
+            Release::Concrete(existing) => existing,
+            Release::Abstract(_) => {
+                *node = Release::Concrete(release);
+                return Ok(());
+            }
+        };
+
+        ensure!(
+            existing.payload == release.payload,
+            "Releases with the same version ({}) have different payloads ({} and {})",
+            existing.version,
+            existing.payload,
+            release.payload
+        );
+        for (key, value) in release.metadata {
+            if let Some(current) = existing.metadata.get(&key) {
+                ensure!(
+                    current == &value,
+                    "Releases with the same version ({}) have conflicting metadata for {}",
+                    existing.version,
+                    key
+                );
+            }
+            existing.metadata.insert(key, value);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> GraphStats {
+        GraphStats {
+            releases: self.release_count(),
+            transitions: self.transition_count(),
+            oldest: self.releases().map(Release::version).min().cloned(),
+            newest: self.releases().map(Release::version).max().cloned(),
+            orphans: self.dag
+                .node_references()
+                .filter(|(id, _)| self.dag.graph().neighbors_undirected(*id).next().is_none())
+                .count(),
+        }
+    }
+
+    /// Lists the releases and transitions which were added or removed in the other graph,
+    /// compared to this one. The lists are sorted by version.
+    pub fn diff(&self, other: &Graph) -> GraphDiff {
+        fn difference<T: Clone + Hash + Ord>(a: &HashSet<T>, b: &HashSet<T>) -> Vec<T> {
+            let mut items: Vec<T> = a.difference(b).cloned().collect();
+            items.sort();
+            items
+        }
+
+        let (releases, other_releases) = (self.versions(), other.versions());
+        let (transitions, other_transitions) = (self.version_pairs(), other.version_pairs());
+        GraphDiff {
+            added_releases: difference(&other_releases, &releases),
+            removed_releases: difference(&releases, &other_releases),
+            added_transitions: difference(&other_transitions, &transitions),
+            removed_transitions: difference(&transitions, &other_transitions),
+        }
+    }
+
+    fn versions(&self) -> HashSet<Version> {
+        self.releases().map(|release| release.version().clone()).collect()
+    }
+
+    fn version_pairs(&self) -> HashSet<(Version, Version)> {
+        self.dag
+            .raw_edges()
+            .iter()
+            .map(|edge| {
+                (
+                    self.dag[edge.source()].version().clone(),
+                    self.dag[edge.target()].version().clone(),
+                )
+            })
+            .collect()
     }
 
     pub fn next_releases(&self, source: &ReleaseId) -> NextReleases {
@@ -136,6 +643,8 @@ impl<'a> Deserialize<'a> for Graph {
         enum Field {
             Edges,
             Nodes,
+            #[serde(rename = "conditionalEdges")]
+            ConditionalEdges,
         }
 
         struct GraphVisitor;
@@ -153,8 +662,15 @@ impl<'a> Deserialize<'a> for Graph {
             {
                 let mut edges: Option<Vec<(daggy::NodeIndex, daggy::NodeIndex)>> = None;
                 let mut nodes: Option<Vec<Release>> = None;
+                let mut conditional_edges: Option<Vec<ConditionalEdge>> = None;
                 while let Some(key) = map.next_key()? {
                     match key {
+                        Field::ConditionalEdges => {
+                            if conditional_edges.is_some() {
+                                return Err(de::Error::duplicate_field("conditionalEdges"));
+                            }
+                            conditional_edges = Some(map.next_value()?);
+                        }
                         Field::Edges => {
                             if edges.is_some() {
                                 return Err(de::Error::duplicate_field("edges"));
@@ -173,10 +689,40 @@ impl<'a> Deserialize<'a> for Graph {
                 let nodes = nodes.ok_or_else(|| de::Error::missing_field("nodes"))?;
                 let mut graph = Graph {
                     dag: Dag::with_capacity(nodes.len(), edges.len()),
+                    conditional_edges: conditional_edges.unwrap_or_default(),
+                    versions: HashMap::with_capacity(nodes.len()),
                 };
-                nodes.into_iter().for_each(|n| {
-                    graph.dag.add_node(n);
-                });
+                for node in nodes {
+                    if graph.versions.contains_key(node.version()) {
+                        return Err(de::Error::custom(format!(
+                            "duplicate release version: {}",
+                            node.version()
+                        )));
+                    }
+                    graph.insert(node);
+                }
+                if let Some(update) = graph
+                    .conditional_edges
+                    .iter()
+                    .flat_map(|edge| &edge.edges)
+                    .find(|update| {
+                        !graph.versions.contains_key(&update.from)
+                            || !graph.versions.contains_key(&update.to)
+                    }) {
+                    return Err(de::Error::custom(format!(
+                        "conditional update refers to an unknown release: {} -> {}",
+                        update.from, update.to
+                    )));
+                }
+                if let Some((s, t)) = edges.iter().find(|(s, t)| {
+                    s.index() >= graph.dag.node_count() || t.index() >= graph.dag.node_count()
+                }) {
+                    return Err(de::Error::custom(format!(
+                        "edge refers to an unknown node: [{}, {}]",
+                        s.index(),
+                        t.index()
+                    )));
+                }
                 graph
                     .dag
                     .add_edges(edges.into_iter().map(|(s, t)| (s, t, Empty {})))
@@ -187,7 +733,44 @@ impl<'a> Deserialize<'a> for Graph {
             }
         }
 
-        deserializer.deserialize_struct("Graph", &["nodes", "edges"], GraphVisitor)
+        deserializer.deserialize_struct(
+            "Graph",
+            &["nodes", "edges", "conditionalEdges"],
+            GraphVisitor,
+        )
+    }
+}
+
+impl Graph {
+    /// Returns the releases sorted by version, along with the transitions between them (as
+    /// indices into the sorted releases) in ascending order, so that identical graphs are always
+    /// serialized identically, regardless of the order in which they were built.
+    fn canonical(&self) -> (Vec<&Release>, Vec<(usize, usize)>) {
+        let mut order: Vec<_> = self.dag.node_references().collect();
+        order.sort_by(|(_, a), (_, b)| a.version().cmp(b.version()));
+
+        let positions: HashMap<_, _> = order
+            .iter()
+            .enumerate()
+            .map(|(position, (id, _))| (*id, position))
+            .collect();
+        let mut edges: Vec<_> = self.dag
+            .raw_edges()
+            .iter()
+            .map(|edge| (positions[&edge.source()], positions[&edge.target()]))
+            .collect();
+        edges.sort();
+
+        (order.into_iter().map(|(_, release)| release).collect(), edges)
+    }
+
+    fn canonical_conditional_edges(&self) -> Vec<ConditionalEdge> {
+        let mut conditional_edges = self.conditional_edges.clone();
+        for edge in &mut conditional_edges {
+            edge.edges
+                .sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        }
+        conditional_edges
     }
 }
 
@@ -196,30 +779,27 @@ impl Serialize for Graph {
     where
         S: Serializer,
     {
-        struct Edges<'a>(&'a [daggy::petgraph::graph::Edge<Empty>]);
-        struct Nodes<'a>(&'a [daggy::petgraph::graph::Node<Release>]);
-
-        impl<'a> Serialize for Edges<'a> {
-            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-            where
-                S: Serializer,
-            {
-                serializer.collect_seq(self.0.iter().map(|edge| (edge.source(), edge.target())))
-            }
-        }
+        let (nodes, edges) = self.canonical();
+        let mut state = serializer.serialize_struct("Graph", 2)?;
+        state.serialize_field("nodes", &nodes)?;
+        state.serialize_field("edges", &edges)?;
+        state.end()
+    }
+}
 
-        impl<'a> Serialize for Nodes<'a> {
-            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-            where
-                S: Serializer,
-            {
-                serializer.collect_seq(self.0.iter().map(|node| &node.weight))
-            }
-        }
+/// A graph serialized according to the v2 schema, which adds the conditional edges.
+pub struct GraphV2<'a>(&'a Graph);
 
-        let mut state = serializer.serialize_struct("Graph", 2)?;
-        state.serialize_field("nodes", &Nodes(&self.dag.raw_nodes()))?;
-        state.serialize_field("edges", &Edges(&self.dag.raw_edges()))?;
+impl<'a> Serialize for GraphV2<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (nodes, edges) = self.0.canonical();
+        let mut state = serializer.serialize_struct("Graph", 3)?;
+        state.serialize_field("nodes", &nodes)?;
+        state.serialize_field("edges", &edges)?;
+        state.serialize_field("conditionalEdges", &self.0.canonical_conditional_edges())?;
         state.end()
     }
 }
@@ -252,12 +832,534 @@ mod tests {
         graph.dag.add_edge(v2, v3, Empty {}).unwrap();
         graph.dag.add_edge(v1, v3, Empty {}).unwrap();
 
-        assert_eq!(serde_json::to_string(&graph).unwrap(), r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{}},{"version":"2.0.0","payload":"image/2.0.0","metadata":{}},{"version":"3.0.0","payload":"image/3.0.0","metadata":{}}],"edges":[[0,1],[1,2],[0,2]]}"#);
+        assert_eq!(serde_json::to_string(&graph).unwrap(), r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{}},{"version":"2.0.0","payload":"image/2.0.0","metadata":{}},{"version":"3.0.0","payload":"image/3.0.0","metadata":{}}],"edges":[[0,1],[0,2],[1,2]]}"#);
+    }
+
+    #[test]
+    fn serialize_graph_stable() {
+        let mut graph = Graph::default();
+        let v3 = graph.dag.add_node(Release::Abstract(AbstractRelease {
+            version: Version::new(3, 0, 0),
+        }));
+        let v1 = graph.dag.add_node(Release::Abstract(AbstractRelease {
+            version: Version::new(1, 0, 0),
+        }));
+        let v2 = graph.dag.add_node(Release::Abstract(AbstractRelease {
+            version: Version::new(2, 0, 0),
+        }));
+        let v4 = graph.dag.add_node(Release::Concrete(ConcreteRelease {
+            version: Version::new(4, 0, 0),
+            payload: String::from("image/4.0.0"),
+            metadata: [("zeta", "1"), ("alpha", "2"), ("mu", "3"), ("beta", "4")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }));
+        graph.dag.add_edge(v2, v3, Empty {}).unwrap();
+        graph.dag.add_edge(v1, v2, Empty {}).unwrap();
+        graph.dag.add_edge(v3, v4, Empty {}).unwrap();
+
+        assert_eq!(serde_json::to_string(&graph).unwrap(), r#"{"nodes":[{"version":"1.0.0"},{"version":"2.0.0"},{"version":"3.0.0"},{"version":"4.0.0","payload":"image/4.0.0","metadata":{"alpha":"2","beta":"4","mu":"3","zeta":"1"}}],"edges":[[0,1],[1,2],[2,3]]}"#);
+    }
+
+    #[test]
+    fn count_graph() {
+        let mut graph = Graph::default();
+        assert_eq!(graph.release_count(), 0);
+        assert_eq!(graph.transition_count(), 0);
+
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        graph.add_transition(&v1, &v2).unwrap();
+
+        assert_eq!(graph.release_count(), 2);
+        assert_eq!(graph.transition_count(), 1);
+        assert_eq!(
+            graph
+                .releases()
+                .map(|release| release.version().to_string())
+                .collect::<Vec<_>>(),
+            vec!["1.0.0", "2.0.0"]
+        );
+    }
+
+    #[test]
+    fn filter_graph() {
+        let mut graph = Graph::default();
+        let v1 = graph.dag.add_node(Release::Concrete(ConcreteRelease {
+            version: Version::new(1, 0, 0),
+            payload: String::from("image/1.0.0"),
+            metadata: HashMap::new(),
+        }));
+        let v2 = graph.dag.add_node(Release::Abstract(AbstractRelease {
+            version: Version::new(2, 0, 0),
+        }));
+        let v3 = graph.dag.add_node(Release::Concrete(ConcreteRelease {
+            version: Version::new(3, 0, 0),
+            payload: String::from("image/3.0.0"),
+            metadata: HashMap::new(),
+        }));
+        graph.dag.add_edge(v1, v2, Empty {}).unwrap();
+        graph.dag.add_edge(v2, v3, Empty {}).unwrap();
+        graph.dag.add_edge(v1, v3, Empty {}).unwrap();
+
+        let filtered = graph.filter(|release| match release {
+            Release::Concrete(_) => true,
+            Release::Abstract(_) => false,
+        });
+        assert_eq!(serde_json::to_string(&filtered).unwrap(), r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{}},{"version":"3.0.0","payload":"image/3.0.0","metadata":{}}],"edges":[[0,1]]}"#);
+    }
+
+    #[test]
+    fn serialize_graph_v2() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        graph
+            .add_conditional_transition(
+                &v1,
+                &v2,
+                vec![Risk {
+                    url: String::from("https://example.com/risk"),
+                    name: String::from("Risk"),
+                    message: String::from("Might break"),
+                    matching_rules: vec![MatchingRule::Always],
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(serde_json::to_string(&graph).unwrap(), r#"{"nodes":[{"version":"1.0.0"},{"version":"2.0.0"}],"edges":[]}"#);
+
+        let json = r#"{"nodes":[{"version":"1.0.0"},{"version":"2.0.0"}],"edges":[],"conditionalEdges":[{"edges":[{"from":"1.0.0","to":"2.0.0"}],"risks":[{"url":"https://example.com/risk","name":"Risk","message":"Might break","matchingRules":[{"type":"Always"}]}]}]}"#;
+        assert_eq!(serde_json::to_string(&graph.v2()).unwrap(), json);
+        assert_eq!(
+            serde_json::to_string(&serde_json::from_str::<Graph>(json).unwrap().v2()).unwrap(),
+            json
+        );
+    }
+
+    #[test]
+    fn reject_cycle() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        graph.add_transition(&v1, &v2).unwrap();
+
+        let err = graph.add_transition(&v2, &v1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Transition from 2.0.0 to 1.0.0 would create a cycle"
+        );
+        assert_eq!(graph.transition_count(), 1);
+    }
+
+    #[test]
+    fn add_transitions() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        let v3 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(3, 0, 0),
+            }))
+            .unwrap();
+        graph.add_transitions(vec![(v1, v2), (v2, v3)]).unwrap();
+        assert_eq!(graph.transition_count(), 2);
+
+        let err = graph.add_transitions(vec![(v1, v3), (v3, v1)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Transition from 3.0.0 to 1.0.0 would create a cycle"
+        );
+        assert_eq!(graph.transition_count(), 2);
+    }
+
+    #[test]
+    fn remove_from_graph() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        let v3 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(3, 0, 0),
+            }))
+            .unwrap();
+        graph.add_transition(&v1, &v2).unwrap();
+        graph.add_transition(&v2, &v3).unwrap();
+        graph.add_transition(&v1, &v3).unwrap();
+
+        graph.remove_transition(&v1, &v3).unwrap();
+        assert!(graph.remove_transition(&v1, &v3).is_err());
+        assert_eq!(graph.transition_count(), 2);
+
+        let removed = graph.remove_release(v1).unwrap();
+        assert_eq!(removed.version(), &Version::new(1, 0, 0));
+        assert_eq!(graph.release_count(), 2);
+        assert_eq!(graph.transition_count(), 1);
+        assert!(graph.find_by_version(&Version::new(1, 0, 0)).is_none());
+        let v3 = graph.find_by_version(&Version::new(3, 0, 0)).unwrap();
+        assert_eq!(graph.release(&v3).unwrap().version(), &Version::new(3, 0, 0));
+
+        let v2 = graph.find_by_version(&Version::new(2, 0, 0)).unwrap();
+        let next: Vec<_> = graph.next_releases(&v2).map(Release::version).collect();
+        assert_eq!(next, vec![&Version::new(3, 0, 0)]);
+    }
+
+    #[test]
+    fn diff_graph() {
+        let mut old = Graph::default();
+        let mut new = Graph::default();
+        for (graph, versions) in vec![(&mut old, vec![1, 2]), (&mut new, vec![2, 3])] {
+            let ids: Vec<_> = versions
+                .into_iter()
+                .map(|major| {
+                    graph
+                        .add_release(Release::Abstract(AbstractRelease {
+                            version: Version::new(major, 0, 0),
+                        }))
+                        .unwrap()
+                })
+                .collect();
+            graph.add_transition(&ids[0], &ids[1]).unwrap();
+        }
+
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(
+            old.diff(&new),
+            GraphDiff {
+                added_releases: vec![Version::new(3, 0, 0)],
+                removed_releases: vec![Version::new(1, 0, 0)],
+                added_transitions: vec![(Version::new(2, 0, 0), Version::new(3, 0, 0))],
+                removed_transitions: vec![(Version::new(1, 0, 0), Version::new(2, 0, 0))],
+            }
+        );
+    }
+
+    #[test]
+    fn merge_graph() {
+        fn release(major: u64, metadata: &[(&str, &str)]) -> Release {
+            Release::Concrete(ConcreteRelease {
+                version: Version::new(major, 0, 0),
+                payload: format!("image/{}.0.0", major),
+                metadata: metadata
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            })
+        }
+
+        let mut a = Graph::default();
+        let v1 = a.add_release(release(1, &[("a", "1")])).unwrap();
+        let v2 = a
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        a.add_transition(&v1, &v2).unwrap();
+
+        let mut b = Graph::default();
+        let v1 = b.add_release(release(1, &[("b", "2")])).unwrap();
+        let v2 = b.add_release(release(2, &[])).unwrap();
+        let v3 = b.add_release(release(3, &[])).unwrap();
+        b.add_transition(&v1, &v2).unwrap();
+        b.add_transition(&v2, &v3).unwrap();
+
+        let merged = a.merge(b).unwrap();
+        assert_eq!(merged.release_count(), 3);
+        assert_eq!(merged.transition_count(), 2);
+        match merged.releases().next().unwrap() {
+            Release::Concrete(release) => assert_eq!(release.metadata.len(), 2),
+            Release::Abstract(_) => panic!("expected a concrete release"),
+        }
+        match merged.releases().nth(1).unwrap() {
+            Release::Concrete(release) => assert_eq!(release.payload, "image/2.0.0"),
+            Release::Abstract(_) => panic!("expected a concrete release"),
+        }
+
+        let mut a = Graph::default();
+        a.add_release(release(1, &[("a", "1")])).unwrap();
+        let mut b = Graph::default();
+        b.add_release(release(1, &[("a", "2")])).unwrap();
+        assert!(a.merge(b).is_err());
+    }
+
+    #[test]
+    fn shortest_path() {
+        let mut graph = Graph::default();
+        let ids: Vec<_> = (1..5)
+            .map(|major| {
+                graph
+                    .add_release(Release::Abstract(AbstractRelease {
+                        version: Version::new(major, 0, 0),
+                    }))
+                    .unwrap()
+            })
+            .collect();
+        graph.add_transition(&ids[0], &ids[1]).unwrap();
+        graph.add_transition(&ids[1], &ids[2]).unwrap();
+        graph.add_transition(&ids[2], &ids[3]).unwrap();
+        graph.add_transition(&ids[1], &ids[3]).unwrap();
+
+        let path: Vec<_> = graph
+            .shortest_path(&Version::new(1, 0, 0), &Version::new(4, 0, 0))
+            .unwrap()
+            .iter()
+            .map(|id| graph.release(id).unwrap().version().clone())
+            .collect();
+        assert_eq!(
+            path,
+            vec![
+                Version::new(1, 0, 0),
+                Version::new(2, 0, 0),
+                Version::new(4, 0, 0),
+            ]
+        );
+
+        assert_eq!(
+            graph
+                .shortest_path(&Version::new(3, 0, 0), &Version::new(3, 0, 0))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            graph
+                .shortest_path(&Version::new(4, 0, 0), &Version::new(1, 0, 0))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn reachability() {
+        let mut graph = Graph::default();
+        let ids: Vec<_> = (1..5)
+            .map(|major| {
+                graph
+                    .add_release(Release::Abstract(AbstractRelease {
+                        version: Version::new(major, 0, 0),
+                    }))
+                    .unwrap()
+            })
+            .collect();
+        graph.add_transition(&ids[0], &ids[1]).unwrap();
+        graph.add_transition(&ids[0], &ids[2]).unwrap();
+        graph.add_transition(&ids[2], &ids[3]).unwrap();
+
+        let versions = |ids: Vec<ReleaseId>| -> Vec<Version> {
+            ids.iter()
+                .map(|id| graph.release(id).unwrap().version().clone())
+                .collect()
+        };
+        assert_eq!(
+            versions(graph.reachable_from(&Version::new(1, 0, 0)).unwrap()),
+            vec![
+                Version::new(2, 0, 0),
+                Version::new(3, 0, 0),
+                Version::new(4, 0, 0),
+            ]
+        );
+        assert!(
+            graph
+                .reachable_from(&Version::new(4, 0, 0))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(graph.reachable_from(&Version::new(5, 0, 0)).is_none());
+        assert_eq!(versions(graph.dead_ends()), vec![Version::new(2, 0, 0)]);
+    }
+
+    #[test]
+    fn modify_metadata() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Concrete(ConcreteRelease {
+                version: Version::new(1, 0, 0),
+                payload: String::from("image/1.0.0"),
+                metadata: HashMap::new(),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+
+        graph
+            .metadata_mut(&v1)
+            .unwrap()
+            .insert(String::from("key"), String::from("value"));
+        assert!(graph.metadata_mut(&v2).is_none());
+        match graph.release(&v1).unwrap() {
+            Release::Concrete(release) => assert_eq!(release.metadata["key"], "value"),
+            Release::Abstract(_) => panic!("expected a concrete release"),
+        }
+    }
+
+    #[test]
+    fn deserialize_mixed_graph() {
+        let json = r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{"key":"value"}},{"version":"2.0.0"}],"edges":[[0,1]]}"#;
+        let graph = serde_json::from_str::<Graph>(json).unwrap();
+        match graph.releases().next().unwrap() {
+            Release::Concrete(release) => {
+                assert_eq!(release.payload, "image/1.0.0");
+                assert_eq!(release.metadata["key"], "value");
+            }
+            Release::Abstract(_) => panic!("expected a concrete release"),
+        }
+        match graph.releases().nth(1).unwrap() {
+            Release::Abstract(release) => assert_eq!(release.version, Version::new(2, 0, 0)),
+            Release::Concrete(_) => panic!("expected an abstract release"),
+        }
+        assert_eq!(serde_json::to_string(&graph).unwrap(), json);
+    }
+
+    #[test]
+    fn deserialize_invalid_graph() {
+        for json in &[
+            r#"{"nodes":[{"version":"1.0.0"}]}"#,
+            r#"{"edges":[]}"#,
+            r#"{"nodes":[{"version":"1.0.0"}],"edges":[[0,1]]}"#,
+            r#"{"nodes":[{"version":"1.0.0"},{"version":"2.0.0"}],"edges":[[0,1],[1,0]]}"#,
+            r#"{"nodes":[{"version":"1.0.0"},{"version":"1.0.0"}],"edges":[]}"#,
+            r#"{"nodes":[{"version":"1.0"}],"edges":[]}"#,
+        ] {
+            assert!(serde_json::from_str::<Graph>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn reject_unknown_conditional_releases() {
+        let json = r#"{"nodes":[{"version":"1.0.0"},{"version":"2.0.0"}],"edges":[],"conditionalEdges":[{"edges":[{"from":"1.0.0","to":"3.0.0"}],"risks":[{"url":"https://example.com/risk","name":"Risk","message":"Might break","matchingRules":[{"type":"Always"}]}]}]}"#;
+        let err = serde_json::from_str::<Graph>(json).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("conditional update refers to an unknown release: 1.0.0 -> 3.0.0"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn releases_in_range() {
+        let mut graph = Graph::default();
+        for version in &["4.2.0", "4.1.0", "4.1.3", "4.0.9"] {
+            graph
+                .add_release(Release::Abstract(AbstractRelease {
+                    version: Version::parse(version).unwrap(),
+                }))
+                .unwrap();
+        }
+
+        let versions: Vec<_> = graph
+            .releases_in_range(&VersionReq::parse(">=4.1.0, <4.2.0").unwrap())
+            .iter()
+            .map(|id| graph.release(id).unwrap().version().to_string())
+            .collect();
+        assert_eq!(versions, vec!["4.1.0", "4.1.3"]);
+    }
+
+    #[test]
+    fn iterate_graph() {
+        let mut graph = Graph::default();
+        let v1 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(1, 0, 0),
+            }))
+            .unwrap();
+        let v2 = graph
+            .add_release(Release::Abstract(AbstractRelease {
+                version: Version::new(2, 0, 0),
+            }))
+            .unwrap();
+        graph.add_transition(&v1, &v2).unwrap();
+
+        assert_eq!(graph.release_ids().collect::<Vec<_>>(), vec![v1, v2]);
+        assert_eq!(graph.transitions().collect::<Vec<_>>(), vec![(v1, v2)]);
+        for (id, release) in graph.release_ids().zip(graph.releases()) {
+            assert_eq!(graph.release(&id).unwrap().version(), release.version());
+        }
+    }
+
+    #[test]
+    fn graph_stats() {
+        assert_eq!(
+            Graph::default().stats(),
+            GraphStats {
+                releases: 0,
+                transitions: 0,
+                oldest: None,
+                newest: None,
+                orphans: 0,
+            }
+        );
+
+        let mut graph = Graph::default();
+        let ids: Vec<_> = (1..4)
+            .map(|major| {
+                graph
+                    .add_release(Release::Abstract(AbstractRelease {
+                        version: Version::new(major, 0, 0),
+                    }))
+                    .unwrap()
+            })
+            .collect();
+        graph.add_transition(&ids[1], &ids[2]).unwrap();
+
+        assert_eq!(
+            graph.stats(),
+            GraphStats {
+                releases: 3,
+                transitions: 1,
+                oldest: Some(Version::new(1, 0, 0)),
+                newest: Some(Version::new(3, 0, 0)),
+                orphans: 1,
+            }
+        );
     }
 
     #[test]
     fn deserialize_graph() {
-        let json = r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{}},{"version":"2.0.0","payload":"image/2.0.0","metadata":{}},{"version":"3.0.0","payload":"image/3.0.0","metadata":{}}],"edges":[[0,1],[1,2],[0,2]]}"#;
+        let json = r#"{"nodes":[{"version":"1.0.0","payload":"image/1.0.0","metadata":{}},{"version":"2.0.0","payload":"image/2.0.0","metadata":{}},{"version":"3.0.0","payload":"image/3.0.0","metadata":{}}],"edges":[[0,1],[0,2],[1,2]]}"#;
         assert_eq!(
             serde_json::to_string(&serde_json::from_str::<Graph>(json).unwrap()).unwrap(),
             json
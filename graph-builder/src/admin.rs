@@ -0,0 +1,52 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{HttpRequest, HttpResponse};
+use graph::State;
+
+#[derive(Serialize)]
+struct CacheEntry {
+    key: String,
+    tag: Option<String>,
+    found: bool,
+}
+
+/// Lists the entries of the metadata cache.
+pub fn list_cache(req: HttpRequest<State>) -> HttpResponse {
+    let entries: Vec<_> = req.state()
+        .cache()
+        .list()
+        .into_iter()
+        .map(|(key, tag, found)| CacheEntry { key, tag, found })
+        .collect();
+    HttpResponse::Ok().json(entries)
+}
+
+/// Evicts an entry from the metadata cache, so that the image is searched again during the next
+/// scan.
+pub fn evict_cache(req: HttpRequest<State>) -> HttpResponse {
+    let key = match req.match_info().get("key") {
+        Some(key) => key.to_string(),
+        None => return HttpResponse::BadRequest().finish(),
+    };
+
+    match req.state().cache().remove(&key) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            error!("Failed to evict cache entry {}: {}", key, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::http::header;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{self, HttpMessage, HttpRequest, HttpResponse};
+use failure::{Error, ResultExt};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Middleware rejecting requests which don't carry the expected bearer token.
+#[derive(Clone)]
+pub struct BearerAuth {
+    token: Arc<String>,
+}
+
+impl BearerAuth {
+    /// Creates a middleware expecting the token stored in the given file.
+    pub fn from_file(path: &Path) -> Result<BearerAuth, Error> {
+        let token = fs::read_to_string(path).context("failed to read auth token")?;
+        let token = token.trim();
+        ensure!(!token.is_empty(), "auth token is empty");
+        Ok(BearerAuth {
+            token: Arc::new(token.to_string()),
+        })
+    }
+
+    fn authorized<S>(&self, req: &HttpRequest<S>) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let mut parts = value.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        Some(token.trim())
+                    }
+                    _ => None,
+                }
+            })
+            .map(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes()))
+            .unwrap_or(false)
+    }
+}
+
+impl<S> Middleware<S> for BearerAuth {
+    fn start(&self, req: &mut HttpRequest<S>) -> actix_web::Result<Started> {
+        if self.authorized(req) {
+            return Ok(Started::Done);
+        }
+        Ok(Started::Response(
+            HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .finish(),
+        ))
+    }
+}
+
+/// Middleware rejecting requests which don't come from the loopback interface.
+#[derive(Clone, Copy)]
+pub struct LoopbackOnly;
+
+impl<S> Middleware<S> for LoopbackOnly {
+    fn start(&self, req: &mut HttpRequest<S>) -> actix_web::Result<Started> {
+        match req.peer_addr() {
+            Some(addr) if addr.ip().is_loopback() => Ok(Started::Done),
+            _ => Ok(Started::Response(HttpResponse::Forbidden().finish())),
+        }
+    }
+}
+
+/// Compares the two byte strings in time independent of their contents, so that the token can't
+/// be guessed by timing the responses.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,606 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use failure::{Error, ResultExt};
+use lru_cache::LruCache;
+use prometheus::{IntCounter, IntGauge};
+use redis::{self, Commands};
+use release::Metadata;
+use rusqlite::Connection;
+use serde_json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref HITS: IntCounter = register_int_counter!(
+        "graph_builder_cache_hits_total",
+        "Number of images whose release metadata was found in the cache"
+    ).unwrap();
+    static ref MISSES: IntCounter = register_int_counter!(
+        "graph_builder_cache_misses_total",
+        "Number of images whose release metadata was missing from the cache"
+    ).unwrap();
+    static ref INSERTS: IntCounter = register_int_counter!(
+        "graph_builder_cache_inserts_total",
+        "Number of entries inserted into the cache"
+    ).unwrap();
+    static ref ENTRIES: IntGauge = register_int_gauge!(
+        "graph_builder_cache_entries",
+        "Number of entries currently held in memory by the cache"
+    ).unwrap();
+}
+
+/// Cache of the release metadata found in each image, so that unchanged images aren't downloaded
+/// again on every scan. Images without release metadata are cached as well, but if a negative TTL
+/// is set, those entries expire so that the images are searched again (e.g. in case the metadata
+/// was pushed after the image was first scanned). If the number of entries is bounded, the least
+/// recently used entries are evicted first.
+///
+/// The entries held in memory and the backend are locked separately, so that a slow backend
+/// doesn't hold up the lookups which are answered from memory.
+pub struct Cache {
+    memory: Mutex<Memory>,
+    backend: Option<Mutex<Box<Backend>>>,
+    negative_ttl: Option<Duration>,
+}
+
+/// Entries held in memory, along with the tags of their images.
+struct Memory {
+    entries: LruCache<String, Entry>,
+    tags: HashMap<String, String>,
+}
+
+impl Memory {
+    /// Holds the given entry, evicting the least recently used entry (along with its tag) if the
+    /// cache is full.
+    fn store(&mut self, key: String, entry: Entry) {
+        if self.entries.len() >= self.entries.capacity() && !self.entries.contains_key(&key) {
+            if let Some((evicted, _)) = self.entries.remove_lru() {
+                self.tags.remove(&evicted);
+            }
+        }
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.tags.remove(key);
+        self.entries.remove(key).is_some()
+    }
+}
+
+/// Result of searching an image for release metadata.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub metadata: Option<Metadata>,
+    /// Time at which the image was searched, in seconds since the Unix epoch
+    pub searched: u64,
+}
+
+impl Entry {
+    fn new(metadata: Option<Metadata>) -> Entry {
+        Entry {
+            metadata,
+            searched: now(),
+        }
+    }
+
+    fn is_expired(&self, negative_ttl: Option<Duration>) -> bool {
+        match (&self.metadata, negative_ttl) {
+            (None, Some(ttl)) => now() >= self.searched + ttl.as_secs(),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Persistent storage for a cache, which is loaded when the cache is created and written to
+/// whenever an entry is inserted. Entries missing from the cache are also looked up in the
+/// backend, since it may be shared with other instances.
+pub trait Backend: Send {
+    fn load(&self) -> Result<Vec<(String, Entry)>, Error>;
+    fn get(&self, key: &str) -> Result<Option<Entry>, Error>;
+    fn store(&self, key: &str, entry: &Entry) -> Result<(), Error>;
+    /// Removes the given entry, returning whether it was stored.
+    fn remove(&self, key: &str) -> Result<bool, Error>;
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            memory: Mutex::new(Memory {
+                entries: LruCache::new(usize::max_value()),
+                tags: HashMap::new(),
+            }),
+            backend: None,
+            negative_ttl: None,
+        }
+    }
+
+    pub fn with_backend(backend: Box<Backend>) -> Result<Cache, Error> {
+        let entries = backend.load().context("failed to load cache")?;
+        debug!("loaded {} cache entries", entries.len());
+        let mut cache = Cache::new();
+        cache.backend = Some(Mutex::new(backend));
+        {
+            let memory = cache.memory();
+            for (key, entry) in entries {
+                memory.store(key, entry);
+            }
+            ENTRIES.set(memory.entries.len() as i64);
+        }
+        Ok(cache)
+    }
+
+    /// Sets the maximum number of entries held in memory.
+    pub fn max_entries(mut self, max: Option<usize>) -> Cache {
+        {
+            let memory = self.memory();
+            memory
+                .entries
+                .set_capacity(max.unwrap_or_else(usize::max_value));
+            let entries = &mut memory.entries;
+            memory.tags.retain(|key, _| entries.contains_key(key));
+            ENTRIES.set(entries.len() as i64);
+        }
+        self
+    }
+
+    /// Sets the duration after which the entries of images without release metadata expire.
+    pub fn negative_ttl(mut self, ttl: Option<Duration>) -> Cache {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Returns the entries held in memory, while the cache is being set up.
+    fn memory(&mut self) -> &mut Memory {
+        self.memory
+            .get_mut()
+            .expect("cache memory lock has been poisoned")
+    }
+
+    fn lock(&self) -> MutexGuard<Memory> {
+        self.memory
+            .lock()
+            .expect("cache memory lock has been poisoned")
+    }
+
+    pub fn get(&self, key: &str) -> Option<Option<Metadata>> {
+        let metadata = self.lookup(key);
+        match metadata {
+            Some(_) => HITS.inc(),
+            None => MISSES.inc(),
+        }
+        ENTRIES.set(self.len() as i64);
+        metadata
+    }
+
+    fn lookup(&self, key: &str) -> Option<Option<Metadata>> {
+        let held = self.lock().entries.get_mut(key).map(|entry| entry.clone());
+        let entry = match held {
+            Some(entry) => entry,
+            None => {
+                let entry = match self.backend {
+                    Some(ref backend) => match backend
+                        .lock()
+                        .expect("cache backend lock has been poisoned")
+                        .get(key)
+                    {
+                        Ok(entry) => entry,
+                        Err(err) => {
+                            warn!("Failed to look up cache entry {}: {}", key, err);
+                            None
+                        }
+                    },
+                    None => None,
+                }?;
+                self.lock().store(key.to_string(), entry.clone());
+                entry
+            }
+        };
+
+        if entry.is_expired(self.negative_ttl) {
+            trace!("cache entry {} has expired", key);
+            self.lock().remove(key);
+            return None;
+        }
+        Some(entry.metadata)
+    }
+
+    /// Records the tag of the image whose metadata is cached under the given key, so that the
+    /// entries can be identified when they are listed. Tags are only kept for as long as their
+    /// entries, so keys which aren't cached are ignored.
+    pub fn tag(&self, key: &str, tag: &str) {
+        let mut memory = self.lock();
+        if memory.entries.contains_key(key) {
+            memory.tags.insert(key.to_string(), tag.to_string());
+        }
+    }
+
+    /// Returns the number of entries held in memory.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Lists the keys of all entries held in memory, along with the tag of the image (if known)
+    /// and whether release metadata was found in it.
+    pub fn list(&self) -> Vec<(String, Option<String>, bool)> {
+        let memory = self.lock();
+        let mut entries: Vec<_> = memory
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+                (
+                    key.clone(),
+                    memory.tags.get(key).cloned(),
+                    entry.metadata.is_some(),
+                )
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Evicts the given entry, both from memory and from the backend. Returns whether the entry
+    /// was held in either of them.
+    pub fn remove(&self, key: &str) -> Result<bool, Error> {
+        let persisted = match self.backend {
+            Some(ref backend) => backend
+                .lock()
+                .expect("cache backend lock has been poisoned")
+                .remove(key)?,
+            None => false,
+        };
+        let mut memory = self.lock();
+        let removed = memory.remove(key);
+        ENTRIES.set(memory.entries.len() as i64);
+        Ok(removed || persisted)
+    }
+
+    pub fn insert(&self, key: String, metadata: Option<Metadata>) {
+        let entry = Entry::new(metadata);
+        if let Some(ref backend) = self.backend {
+            let backend = backend.lock().expect("cache backend lock has been poisoned");
+            if let Err(err) = backend.store(&key, &entry) {
+                warn!("Failed to persist cache entry {}: {}", key, err);
+            }
+        }
+        let mut memory = self.lock();
+        memory.store(key, entry);
+        INSERTS.inc();
+        ENTRIES.set(memory.entries.len() as i64);
+    }
+}
+
+/// Cache backend storing its entries in an SQLite database.
+pub struct Sqlite {
+    conn: Connection,
+}
+
+impl Sqlite {
+    pub fn open(path: &Path) -> Result<Sqlite, Error> {
+        Sqlite::new(Connection::open(path).context("failed to open cache database")?)
+    }
+
+    fn new(conn: Connection) -> Result<Sqlite, Error> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS releases \
+             (key TEXT PRIMARY KEY, metadata TEXT, searched INTEGER NOT NULL)",
+            &[],
+        )?;
+        Ok(Sqlite { conn })
+    }
+
+    fn entry(metadata: Option<String>, searched: i64) -> Result<Entry, Error> {
+        let metadata = match metadata {
+            Some(json) => Some(serde_json::from_str(&json)?),
+            None => None,
+        };
+        Ok(Entry {
+            metadata,
+            searched: searched as u64,
+        })
+    }
+}
+
+impl Backend for Sqlite {
+    fn load(&self) -> Result<Vec<(String, Entry)>, Error> {
+        let mut stmt = self.conn
+            .prepare("SELECT key, metadata, searched FROM releases")?;
+        let rows = stmt.query_map(&[], |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, Option<String>>(1),
+                row.get::<_, i64>(2),
+            )
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (key, metadata, searched) = row?;
+            entries.push((key, Sqlite::entry(metadata, searched)?));
+        }
+        Ok(entries)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Entry>, Error> {
+        let mut stmt = self.conn
+            .prepare("SELECT metadata, searched FROM releases WHERE key = ?1")?;
+        let mut rows = stmt.query_map(&[&key], |row| {
+            (row.get::<_, Option<String>>(0), row.get::<_, i64>(1))
+        })?;
+        match rows.next() {
+            Some(row) => {
+                let (metadata, searched) = row?;
+                Ok(Some(Sqlite::entry(metadata, searched)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn store(&self, key: &str, entry: &Entry) -> Result<(), Error> {
+        let json = match entry.metadata {
+            Some(ref metadata) => Some(serde_json::to_string(metadata)?),
+            None => None,
+        };
+        self.conn.execute(
+            "INSERT OR REPLACE INTO releases (key, metadata, searched) VALUES (?1, ?2, ?3)",
+            &[&key, &json, &(entry.searched as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, Error> {
+        let deleted = self.conn
+            .execute("DELETE FROM releases WHERE key = ?1", &[&key])?;
+        Ok(deleted > 0)
+    }
+}
+
+/// Cache backend storing its entries in Redis, so that it can be shared by several instances.
+/// Entries are stored as JSON documents and aren't loaded up front, since the cache may be large.
+pub struct Redis {
+    conn: redis::Connection,
+}
+
+impl Redis {
+    const KEY_PREFIX: &'static str = "graph-builder:metadata:";
+
+    /// Connects to the given server, giving up on requests which aren't answered within the given
+    /// timeout so that a stalled server can't hold up the scans.
+    pub fn open(url: &str, timeout: Duration) -> Result<Redis, Error> {
+        let conn = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .context("failed to connect to redis")?;
+        conn.set_read_timeout(Some(timeout))?;
+        conn.set_write_timeout(Some(timeout))?;
+        Ok(Redis { conn })
+    }
+}
+
+impl Backend for Redis {
+    fn load(&self) -> Result<Vec<(String, Entry)>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Entry>, Error> {
+        let json: Option<String> = self.conn.get(format!("{}{}", Self::KEY_PREFIX, key))?;
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&self, key: &str, entry: &Entry) -> Result<(), Error> {
+        let _: () = self.conn.set(
+            format!("{}{}", Self::KEY_PREFIX, key),
+            serde_json::to_string(entry)?,
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, Error> {
+        let deleted: i64 = self.conn.del(format!("{}{}", Self::KEY_PREFIX, key))?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Backend holding its entries in memory, which starts out empty so that entries are only
+    /// found in the cache once they have been looked up.
+    #[derive(Default)]
+    struct InMemory {
+        entries: Mutex<HashMap<String, Entry>>,
+    }
+
+    impl Backend for InMemory {
+        fn load(&self) -> Result<Vec<(String, Entry)>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn get(&self, key: &str) -> Result<Option<Entry>, Error> {
+            Ok(self.entries.lock().unwrap().get(key).cloned())
+        }
+
+        fn store(&self, key: &str, entry: &Entry) -> Result<(), Error> {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), entry.clone());
+            Ok(())
+        }
+
+        fn remove(&self, key: &str) -> Result<bool, Error> {
+            Ok(self.entries.lock().unwrap().remove(key).is_some())
+        }
+    }
+
+    fn metadata(version: &str) -> Metadata {
+        serde_json::from_str(&format!(
+            r#"{{"kind": "cincinnati-metadata-v0", "version": "{}"}}"#,
+            version
+        )).unwrap()
+    }
+
+    /// Returns the version found in the cache under the given key, if any.
+    fn version(cache: &Cache, key: &str) -> Option<Option<String>> {
+        cache
+            .get(key)
+            .map(|metadata| metadata.map(|metadata| metadata.version.to_string()))
+    }
+
+    #[test]
+    fn remove_entries() {
+        let backend = InMemory::default();
+        backend.store("persisted", &Entry::new(None)).unwrap();
+        let cache = Cache::with_backend(Box::new(backend)).unwrap();
+        cache.insert("held".to_string(), None);
+
+        assert_eq!(cache.len(), 1);
+        assert!(cache.remove("persisted").unwrap());
+        assert!(cache.remove("held").unwrap());
+        assert!(!cache.remove("held").unwrap());
+        assert!(!cache.remove("missing").unwrap());
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get("persisted").is_none());
+    }
+
+    #[test]
+    fn expire_negative_entries() {
+        let backend = InMemory::default();
+        let searched = now() - 3600;
+        let entry = |metadata| Entry { metadata, searched };
+        backend.store("negative", &entry(None)).unwrap();
+        backend.store("positive", &entry(Some(metadata("1.0.0")))).unwrap();
+        let cache = Cache::with_backend(Box::new(backend))
+            .unwrap()
+            .negative_ttl(Some(Duration::from_secs(600)));
+
+        assert_eq!(version(&cache, "negative"), None);
+        assert_eq!(version(&cache, "positive"), Some(Some("1.0.0".to_string())));
+
+        // Entries which were just searched haven't expired yet.
+        cache.insert("negative".to_string(), None);
+        assert_eq!(version(&cache, "negative"), Some(None));
+    }
+
+    #[test]
+    fn keep_negative_entries_without_ttl() {
+        let backend = InMemory::default();
+        let entry = Entry {
+            metadata: None,
+            searched: 0,
+        };
+        backend.store("negative", &entry).unwrap();
+        let cache = Cache::with_backend(Box::new(backend)).unwrap();
+        assert_eq!(version(&cache, "negative"), Some(None));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = Cache::new().max_entries(Some(2));
+        cache.insert("a".to_string(), None);
+        cache.insert("b".to_string(), None);
+        cache.tag("a", "4.0.0");
+        cache.tag("b", "4.0.1");
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c".to_string(), None);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b").is_none());
+        assert_eq!(
+            cache.list(),
+            vec![
+                ("a".to_string(), Some("4.0.0".to_string()), false),
+                ("c".to_string(), None, false),
+            ]
+        );
+
+        // Shrinking the cache evicts entries along with their tags.
+        let cache = cache.max_entries(Some(1));
+        assert_eq!(cache.list(), vec![("c".to_string(), None, false)]);
+        cache.tag("c", "4.0.2");
+        cache.insert("a".to_string(), None);
+        assert_eq!(cache.list(), vec![("a".to_string(), None, false)]);
+    }
+
+    #[test]
+    fn tag_held_entries() {
+        let cache = Cache::new();
+        cache.insert("sha256:1111".to_string(), Some(metadata("4.0.0")));
+        cache.insert("sha256:2222".to_string(), None);
+        cache.tag("sha256:1111", "4.0.0");
+        cache.tag("sha256:3333", "4.0.1");
+        assert_eq!(
+            cache.list(),
+            vec![
+                ("sha256:1111".to_string(), Some("4.0.0".to_string()), true),
+                ("sha256:2222".to_string(), None, false),
+            ]
+        );
+
+        cache.remove("sha256:1111").unwrap();
+        cache.insert("sha256:1111".to_string(), None);
+        assert_eq!(cache.list()[0], ("sha256:1111".to_string(), None, false));
+    }
+
+    #[test]
+    fn persist_entries_in_sqlite() {
+        let sqlite = Sqlite::new(Connection::open_in_memory().unwrap()).unwrap();
+        let entry = |metadata| Entry {
+            metadata,
+            searched: 42,
+        };
+        sqlite.store("positive", &entry(Some(metadata("1.0.0")))).unwrap();
+        sqlite.store("negative", &entry(None)).unwrap();
+
+        let mut loaded: Vec<_> = sqlite
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(key, entry)| {
+                let version = entry.metadata.map(|metadata| metadata.version.to_string());
+                (key, version, entry.searched)
+            })
+            .collect();
+        loaded.sort();
+        assert_eq!(
+            loaded,
+            vec![
+                ("negative".to_string(), None, 42),
+                ("positive".to_string(), Some("1.0.0".to_string()), 42),
+            ]
+        );
+
+        let entry = sqlite.get("negative").unwrap().unwrap();
+        assert!(entry.metadata.is_none());
+        assert_eq!(entry.searched, 42);
+        assert!(sqlite.get("missing").unwrap().is_none());
+
+        assert!(sqlite.remove("negative").unwrap());
+        assert!(!sqlite.remove("negative").unwrap());
+        assert!(sqlite.get("negative").unwrap().is_none());
+    }
+}
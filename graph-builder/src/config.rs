@@ -12,10 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use failure::{Error, ResultExt};
+use graph_data;
+use release;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::net::IpAddr;
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
+use structopt;
+use toml::{self, Value};
+use url::Url;
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
@@ -23,27 +33,534 @@ pub struct Options {
     #[structopt(short = "v", parse(from_occurrences))]
     pub verbosity: u64,
 
+    /// TOML file setting any of the options (by long flag name) not given on the command line
+    #[structopt(long = "config", parse(from_os_str), raw(env = r#""GB_CONFIG""#))]
+    pub config: Option<PathBuf>,
+
+    /// Source of the release metadata (registry, file, or s3)
+    #[structopt(long = "source", default_value = "registry", raw(env = r#""GB_SOURCE""#))]
+    pub source: Source,
+
+    /// Directory of release metadata documents, used by the file source
+    #[structopt(long = "path", parse(from_os_str), raw(env = r#""GB_PATH""#))]
+    pub path: Option<PathBuf>,
+
+    /// Bucket containing release metadata documents, used by the s3 source
+    #[structopt(long = "bucket", raw(env = r#""GB_BUCKET""#))]
+    pub bucket: Option<String>,
+
+    /// Prefix of the release metadata documents within the bucket
+    #[structopt(long = "prefix", default_value = "", raw(env = r#""GB_PREFIX""#))]
+    pub prefix: String,
+
+    /// Region of the bucket
+    #[structopt(long = "s3-region", default_value = "us-east-1", raw(env = r#""GB_S3_REGION""#))]
+    pub s3_region: String,
+
+    /// Endpoint of an S3-compatible object store, overriding the region's default
+    #[structopt(long = "s3-endpoint", raw(env = r#""GB_S3_ENDPOINT""#))]
+    pub s3_endpoint: Option<String>,
+
     /// URL for the container image registry
-    #[structopt(long = "registry", default_value = "http://localhost:5000")]
+    #[structopt(
+        long = "registry",
+        default_value = "http://localhost:5000",
+        raw(env = r#""GB_REGISTRY""#)
+    )]
     pub registry: String,
 
+    /// URL for a mirror of the registry, used when the registry is unavailable (may be repeated)
+    #[structopt(long = "mirror", raw(env = r#""GB_MIRROR""#))]
+    pub mirrors: Vec<String>,
+
     /// Name of the container image repository
-    #[structopt(long = "repository", default_value = "openshift")]
+    #[structopt(long = "repository", default_value = "openshift", raw(env = r#""GB_REPOSITORY""#))]
     pub repository: String,
 
+    /// AWS region of the ECR registry, whose credentials are obtained from the environment's role
+    #[structopt(
+        long = "ecr-region",
+        raw(env = r#""GB_ECR_REGION""#, conflicts_with = r#""gcp_service_account""#)
+    )]
+    pub ecr_region: Option<String>,
+
+    /// GCP service account key file (JSON) with which to authenticate to GCR or Artifact Registry
+    #[structopt(
+        long = "gcp-service-account",
+        parse(from_os_str),
+        raw(env = r#""GB_GCP_SERVICE_ACCOUNT""#)
+    )]
+    pub gcp_service_account: Option<PathBuf>,
+
+    /// Docker config file or mounted pull secret holding registry credentials
+    #[structopt(
+        long = "credentials-path",
+        parse(from_os_str),
+        raw(
+            env = r#""GB_CREDENTIALS_PATH""#,
+            conflicts_with_all = r#"&["ecr_region", "gcp_service_account"]"#
+        )
+    )]
+    pub credentials_path: Option<PathBuf>,
+
+    /// Read registry credentials from the current user's docker config if no other source is given
+    #[structopt(long = "user-docker-config")]
+    pub user_docker_config: bool,
+
+    /// Retry anonymously (with a warning) if the registry rejects the credentials
+    #[structopt(long = "anonymous-fallback")]
+    pub anonymous_fallback: bool,
+
+    /// Git repository containing curated graph data
+    #[structopt(long = "graph-data-repo", raw(env = r#""GB_GRAPH_DATA_REPO""#))]
+    pub graph_data_repo: Option<String>,
+
+    /// Branch or tag of the graph data repository
+    #[structopt(
+        long = "graph-data-ref",
+        default_value = "master",
+        raw(env = r#""GB_GRAPH_DATA_REF""#)
+    )]
+    pub graph_data_ref: String,
+
+    /// Directory in which the graph data repository is checked out
+    #[structopt(
+        long = "graph-data-dir",
+        default_value = "graph-data",
+        parse(from_os_str),
+        raw(env = r#""GB_GRAPH_DATA_DIR""#)
+    )]
+    pub graph_data_dir: PathBuf,
+
+    /// Tag to scan, excluding any tags that aren't allowed (may be repeated)
+    #[structopt(long = "tag-allow", raw(env = r#""GB_TAG_ALLOW""#))]
+    pub tag_allow: Vec<String>,
+
+    /// File listing tags to scan (one per line), in addition to the allowed tags
+    #[structopt(long = "tag-allow-file", parse(from_os_str), raw(env = r#""GB_TAG_ALLOW_FILE""#))]
+    pub tag_allow_file: Option<PathBuf>,
+
+    /// Tag to exclude from the scan, even if it is allowed (may be repeated)
+    #[structopt(long = "tag-deny", raw(env = r#""GB_TAG_DENY""#))]
+    pub tag_deny: Vec<String>,
+
+    /// File listing tags to exclude from the scan (one per line), in addition to the denied tags
+    #[structopt(long = "tag-deny-file", parse(from_os_str), raw(env = r#""GB_TAG_DENY_FILE""#))]
+    pub tag_deny_file: Option<PathBuf>,
+
+    /// Maximum number of images to read release metadata from, keeping the most recently created
+    #[structopt(long = "max-tags", raw(env = r#""GB_MAX_TAGS""#))]
+    pub max_tags: Option<usize>,
+
     /// Duration of the pause (in seconds) between scans of the registry
-    #[structopt(long = "period", default_value = "30", parse(try_from_str = "parse_duration"))]
+    #[structopt(
+        long = "period",
+        default_value = "30",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_PERIOD""#)
+    )]
     pub period: Duration,
 
+    /// Maximum percentage of the releases a scan may drop before it is rejected as a failure
+    #[structopt(long = "max-release-loss", raw(env = r#""GB_MAX_RELEASE_LOSS""#))]
+    pub max_release_loss: Option<u8>,
+
+    /// Maximum random delay (in seconds) added to the period before each scan
+    #[structopt(
+        long = "period-jitter",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_PERIOD_JITTER""#)
+    )]
+    pub period_jitter: Option<Duration>,
+
+    /// Maximum period (in seconds) to back off to, doubling the period after each failed scan
+    #[structopt(
+        long = "max-backoff",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_MAX_BACKOFF""#)
+    )]
+    pub max_backoff: Option<Duration>,
+
+    /// SQLite database in which the release metadata of scanned images is cached
+    #[structopt(long = "cache-path", parse(from_os_str), raw(env = r#""GB_CACHE_PATH""#))]
+    pub cache_path: Option<PathBuf>,
+
+    /// URL of a Redis server in which the release metadata of scanned images is cached
+    #[structopt(
+        long = "cache-redis",
+        raw(conflicts_with = r#""cache_path""#),
+        raw(env = r#""GB_CACHE_REDIS""#)
+    )]
+    pub cache_redis: Option<String>,
+
+    /// Duration (in seconds) after which a request to the Redis server is abandoned
+    #[structopt(
+        long = "cache-redis-timeout",
+        default_value = "5",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_CACHE_REDIS_TIMEOUT""#)
+    )]
+    pub cache_redis_timeout: Duration,
+
+    /// Duration (in seconds) after which images without release metadata are searched again
+    #[structopt(
+        long = "cache-negative-ttl",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_CACHE_NEGATIVE_TTL""#)
+    )]
+    pub cache_negative_ttl: Option<Duration>,
+
+    /// Maximum number of cache entries held in memory
+    #[structopt(long = "cache-max-entries", raw(env = r#""GB_CACHE_MAX_ENTRIES""#))]
+    pub cache_max_entries: Option<usize>,
+
+    /// File in which the graph is saved after each scan, and from which it is restored on start
+    #[structopt(long = "state-file", parse(from_os_str), raw(env = r#""GB_STATE_FILE""#))]
+    pub state_file: Option<PathBuf>,
+
+    /// Architecture of the graph served to clients that don't request one
+    #[structopt(long = "default-arch", default_value = "amd64", raw(env = r#""GB_DEFAULT_ARCH""#))]
+    pub default_arch: String,
+
+    /// Handling of releases found in more than one image (error, prefer-newest-tag, or
+    /// prefer-digest-match)
+    #[structopt(
+        long = "duplicate-versions",
+        default_value = "error",
+        raw(env = r#""GB_DUPLICATE_VERSIONS""#)
+    )]
+    pub duplicate_versions: DuplicatePolicy,
+
+    /// Metadata key listing the (comma-separated) channels of each release
+    #[structopt(
+        long = "channel-key",
+        raw(default_value = "graph_data::CHANNELS_KEY"),
+        raw(env = r#""GB_CHANNEL_KEY""#)
+    )]
+    pub channel_key: String,
+
+    /// Metadata key marking releases which are left out of the graph
+    #[structopt(
+        long = "hidden-key",
+        raw(default_value = "release::HIDDEN_KEY"),
+        raw(env = r#""GB_HIDDEN_KEY""#)
+    )]
+    pub hidden_key: String,
+
+    /// Where the release metadata of each image is read from (layers or labels)
+    #[structopt(
+        long = "metadata-source",
+        default_value = "layers",
+        raw(env = r#""GB_METADATA_SOURCE""#)
+    )]
+    pub metadata_source: MetadataSource,
+
+    /// Path within the image of the release metadata document (may be repeated)
+    #[structopt(
+        long = "metadata-path",
+        default_value = "cincinnati.json",
+        parse(from_os_str),
+        raw(env = r#""GB_METADATA_PATH""#)
+    )]
+    pub metadata_paths: Vec<PathBuf>,
+
+    /// Metadata key holding each release's manifest digest, which is checked against the registry
+    #[structopt(long = "verify-digest-key", raw(env = r#""GB_VERIFY_DIGEST_KEY""#))]
+    pub verify_digest_key: Option<String>,
+
+    /// Maximum size (in bytes) of the image layers searched for release metadata
+    #[structopt(long = "max-layer-size", raw(env = r#""GB_MAX_LAYER_SIZE""#))]
+    pub max_layer_size: Option<u64>,
+
+    /// Maximum number of image manifests fetched at once when scanning the registry
+    #[structopt(
+        long = "manifest-concurrency",
+        default_value = "4",
+        raw(env = r#""GB_MANIFEST_CONCURRENCY""#)
+    )]
+    pub manifest_concurrency: usize,
+
+    /// Maximum number of image layers downloaded at once when searching for release metadata
+    #[structopt(
+        long = "layer-concurrency",
+        default_value = "4",
+        raw(env = r#""GB_LAYER_CONCURRENCY""#)
+    )]
+    pub layer_concurrency: usize,
+
+    /// Duration (in seconds) after which a scan of the registry is abandoned
+    #[structopt(
+        long = "scan-timeout",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_SCAN_TIMEOUT""#)
+    )]
+    pub scan_timeout: Option<Duration>,
+
+    /// Pre-rendered graph to serve (for the default architecture) instead of scanning
+    #[structopt(
+        long = "serve-from",
+        parse(from_os_str),
+        raw(env = r#""GB_SERVE_FROM""#, conflicts_with = r#""oneshot""#)
+    )]
+    pub serve_from: Option<PathBuf>,
+
+    /// Scan once, write the graph of the default architecture, and exit instead of serving it
+    #[structopt(long = "oneshot")]
+    pub oneshot: bool,
+
+    /// File to which the graph is written in oneshot mode, instead of stdout
+    #[structopt(
+        long = "output",
+        parse(from_os_str),
+        raw(env = r#""GB_OUTPUT""#, requires = r#""oneshot""#)
+    )]
+    pub output: Option<PathBuf>,
+
+    /// Time (in seconds) since the last successful scan after which graphs are marked stale
+    #[structopt(
+        long = "stale-after",
+        parse(try_from_str = "parse_duration"),
+        raw(env = r#""GB_STALE_AFTER""#)
+    )]
+    pub stale_after: Option<Duration>,
+
     /// Address on which the server will listen
-    #[structopt(long = "address", default_value = "127.0.0.1")]
+    #[structopt(long = "address", default_value = "127.0.0.1", raw(env = r#""GB_ADDRESS""#))]
     pub address: IpAddr,
 
     /// Port to which the server will bind
-    #[structopt(long = "port", default_value = "8080")]
+    #[structopt(long = "port", default_value = "8080", raw(env = r#""GB_PORT""#))]
     pub port: u16,
+
+    /// File containing the bearer token which clients must present to fetch the graph or status
+    #[structopt(
+        long = "require-auth-token",
+        parse(from_os_str),
+        raw(env = r#""GB_REQUIRE_AUTH_TOKEN""#)
+    )]
+    pub require_auth_token: Option<PathBuf>,
+
+    /// PEM-encoded RSA or P-256 private key with which the served graphs are signed
+    #[structopt(long = "signing-key", parse(from_os_str), raw(env = r#""GB_SIGNING_KEY""#))]
+    pub signing_key: Option<PathBuf>,
+
+    /// Maximum number of graph requests per second accepted from each client address
+    #[structopt(long = "rate-limit", raw(env = r#""GB_RATE_LIMIT""#))]
+    pub rate_limit: Option<u32>,
+
+    /// Number of graph requests a client may burst above the rate limit
+    #[structopt(
+        long = "rate-limit-burst",
+        default_value = "10",
+        raw(env = r#""GB_RATE_LIMIT_BURST""#)
+    )]
+    pub rate_limit_burst: u32,
+
+    /// OTLP/HTTP endpoint to which trace spans are exported (e.g. http://localhost:4318/v1/traces)
+    #[structopt(long = "otlp-endpoint", raw(env = r#""GB_OTLP_ENDPOINT""#))]
+    pub otlp_endpoint: Option<Url>,
+
+    /// Service name under which trace spans are exported
+    #[structopt(
+        long = "trace-service-name",
+        default_value = "graph-builder",
+        raw(env = r#""GB_TRACE_SERVICE_NAME""#)
+    )]
+    pub trace_service_name: String,
+
+    /// PEM-encoded certificate chain with which the server will serve TLS
+    #[structopt(
+        long = "cert",
+        parse(from_os_str),
+        raw(requires = r#""key""#),
+        raw(env = r#""GB_CERT""#)
+    )]
+    pub cert: Option<PathBuf>,
+
+    /// PEM-encoded private key of the TLS certificate
+    #[structopt(
+        long = "key",
+        parse(from_os_str),
+        raw(requires = r#""cert""#),
+        raw(env = r#""GB_KEY""#)
+    )]
+    pub key: Option<PathBuf>,
+}
+
+impl Options {
+    /// Parses the options from the command line and the environment. If a configuration file is
+    /// given, any of the options it sets are passed as though they followed the ones given on the
+    /// command line, unless the command line or the environment already sets them. Flags (i.e.
+    /// boolean options) can't be set from the environment, so only the command line overrides the
+    /// file for them. Invalid settings in the file are reported as an error, so that they can be
+    /// rejected on reload.
+    pub fn load() -> Result<Options, Error> {
+        let mut args: Vec<OsString> = env::args_os().collect();
+        let opts: Options = structopt::StructOpt::from_iter(args.clone());
+        let path = match opts.config {
+            Some(ref path) => path.clone(),
+            None => return Ok(opts),
+        };
+
+        let given: Vec<String> = args.iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        for (key, value) in read_config(&path)? {
+            if !is_overridden(&key, &value, &given) {
+                args.extend(config_args(&format!("--{}", key), value)?);
+            }
+        }
+        let matches = <Options as structopt::StructOpt>::clap()
+            .get_matches_from_safe(args)
+            .map_err(|err| format_err!("invalid configuration in {}: {}", path.display(), err))?;
+        Ok(structopt::StructOpt::from_clap(&matches))
+    }
+}
+
+/// Returns the name of the environment variable setting the option with the given long flag name.
+fn env_var(key: &str) -> String {
+    format!("GB_{}", key.to_uppercase().replace('-', "_"))
+}
+
+/// Checks whether the option with the given long flag name, which the configuration file sets to
+/// the given value, is already set by the given command-line arguments or by the environment.
+fn is_overridden(key: &str, value: &Value, args: &[String]) -> bool {
+    let flag = format!("--{}", key);
+    args.iter()
+        .any(|arg| *arg == flag || arg.starts_with(&format!("{}=", flag)))
+        || (!value.is_bool() && env::var_os(env_var(key)).is_some())
+}
+
+fn read_config(path: &Path) -> Result<toml::value::Table, Error> {
+    let contents = fs::read_to_string(path)
+        .context(format!("failed to read configuration from {}", path.display()))?;
+    Ok(toml::from_str(&contents)
+        .context(format!("failed to parse configuration from {}", path.display()))?)
+}
+
+/// Formats the command-line arguments equivalent to setting the given flag to the given value.
+/// Arrays repeat the flag for each of their values, and booleans set the flag only if true.
+fn config_args(flag: &str, value: Value) -> Result<Vec<OsString>, Error> {
+    Ok(match value {
+        Value::String(value) => vec![format!("{}={}", flag, value).into()],
+        Value::Integer(value) => vec![format!("{}={}", flag, value).into()],
+        Value::Float(value) => vec![format!("{}={}", flag, value).into()],
+        Value::Boolean(true) => vec![flag.into()],
+        Value::Boolean(false) => Vec::new(),
+        Value::Array(values) => {
+            let mut args = Vec::new();
+            for value in values {
+                args.extend(config_args(flag, value)?);
+            }
+            args
+        }
+        _ => bail!("unsupported configuration value for {}", flag),
+    })
+}
+
+#[derive(Debug)]
+pub enum Source {
+    Registry,
+    File,
+    S3,
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<Source, Error> {
+        match src {
+            "registry" => Ok(Source::Registry),
+            "file" => Ok(Source::File),
+            "s3" => Ok(Source::S3),
+            _ => bail!("unknown release source: {}", src),
+        }
+    }
+}
+
+/// Handling of releases of the same version found in different images: either the scan fails, or
+/// the release from the most recently created image or from the image whose manifest digest
+/// matches the one recorded in the release metadata (under the key given by `verify_digest_key`)
+/// or in its pull spec is kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    Error,
+    Newest,
+    DigestMatch,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<DuplicatePolicy, Error> {
+        match src {
+            "error" => Ok(DuplicatePolicy::Error),
+            "prefer-newest-tag" => Ok(DuplicatePolicy::Newest),
+            "prefer-digest-match" => Ok(DuplicatePolicy::DigestMatch),
+            _ => bail!("unknown duplicate version policy: {}", src),
+        }
+    }
+}
+
+/// Location of the release metadata within an image: either a document within one of its layers,
+/// or its labels, which can be read from the manifest without downloading any layers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataSource {
+    Layers,
+    Labels,
+}
+
+impl FromStr for MetadataSource {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<MetadataSource, Error> {
+        match src {
+            "layers" => Ok(MetadataSource::Layers),
+            "labels" => Ok(MetadataSource::Labels),
+            _ => bail!("unknown metadata source: {}", src),
+        }
+    }
 }
 
 fn parse_duration(src: &str) -> Result<Duration, ParseIntError> {
     Ok(Duration::from_secs(u64::from_str(src)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(value: &str) -> Result<Vec<OsString>, Error> {
+        let table: toml::value::Table = toml::from_str(&format!("value = {}", value)).unwrap();
+        config_args("--flag", table["value"].clone())
+    }
+
+    #[test]
+    fn format_config_args() {
+        assert_eq!(args(r#""value""#).unwrap(), vec!["--flag=value"]);
+        assert_eq!(args("42").unwrap(), vec!["--flag=42"]);
+        assert_eq!(args("0.5").unwrap(), vec!["--flag=0.5"]);
+        assert_eq!(args("true").unwrap(), vec!["--flag"]);
+        assert!(args("false").unwrap().is_empty());
+        assert_eq!(args(r#"["a", "b"]"#).unwrap(), vec!["--flag=a", "--flag=b"]);
+    }
+
+    #[test]
+    fn reject_unsupported_config_values() {
+        assert!(args(r#"{ key = "value" }"#).is_err());
+        assert!(args("1979-05-27T07:32:00Z").is_err());
+    }
+
+    #[test]
+    fn override_config_values() {
+        let args = vec!["graph-builder".to_string(), "--port=8081".to_string()];
+        assert!(is_overridden("port", &Value::Integer(8080), &args));
+        assert!(!is_overridden("address", &Value::String("::".to_string()), &args));
+
+        env::set_var("GB_TEST_OVERRIDE", "value");
+        assert!(is_overridden("test-override", &Value::String("file".to_string()), &args));
+        assert!(!is_overridden("test-override", &Value::Boolean(true), &args));
+        env::remove_var("GB_TEST_OVERRIDE");
+        assert!(!is_overridden("test-override", &Value::String("file".to_string()), &args));
+    }
+}
@@ -0,0 +1,673 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Credentials with which the registry client authenticates. The credentials are sent to the
+//! registry using basic authentication, or are exchanged for a bearer token if the registry
+//! challenges the client to do so.
+
+use config;
+use failure::{Error, ResultExt};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use reqwest;
+use rusoto_core::Region;
+use rusoto_ecr::{Ecr as EcrApi, EcrClient, GetAuthorizationTokenRequest};
+use serde_json;
+use signing::base64url;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, fs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Source of the credentials for a registry. Credentials may expire, so they are requested again
+/// for every request to the registry; providers are expected to cache them.
+pub trait Provider: Send + Sync {
+    /// Returns the credentials for the registry with the given host, if there are any.
+    fn credentials(&self, registry: &str) -> Result<Option<Credentials>, Error>;
+}
+
+/// Returns the provider of registry credentials selected by the given options, if any. Unless
+/// another provider is selected, the docker configuration at the given credentials path is used,
+/// or else the one of the current user (if there is one and it was asked for).
+pub fn provider(opts: &config::Options) -> Result<Option<Arc<Provider>>, Error> {
+    if let Some(ref region) = opts.ecr_region {
+        return Ok(Some(Arc::new(Ecr::new(region.parse()?))));
+    }
+    if let Some(ref path) = opts.gcp_service_account {
+        return Ok(Some(Arc::new(Gcp::from_file(path)?)));
+    }
+    let path = match opts.credentials_path {
+        Some(ref path) => Some(path.clone()),
+        None if opts.user_docker_config => default_docker_config(),
+        None => None,
+    };
+    Ok(match path {
+        Some(path) => Some(Arc::new(DockerConfig::from_file(&path)?)),
+        None => None,
+    })
+}
+
+/// Decodes the given base64 encoding of `<username>:<password>`.
+fn decode_auth(auth: &str) -> Result<Credentials, Error> {
+    let auth = String::from_utf8(base64::decode_block(auth)?)?;
+    let mut parts = auth.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(username), Some(password)) => Ok(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }),
+        _ => bail!("malformed credentials"),
+    }
+}
+
+/// Returns the host of the given registry, without its port (if any).
+fn strip_port(registry: &str) -> &str {
+    registry.split(':').next().unwrap_or(registry)
+}
+
+/// Credentials which are valid until they expire, and are then fetched again.
+struct Expiring(Mutex<Option<(Credentials, SystemTime)>>);
+
+impl Expiring {
+    /// Duration before the expiration of the credentials after which they are no longer used, so
+    /// that they don't expire while in use.
+    const MARGIN: Duration = Duration::from_secs(300);
+
+    fn new() -> Expiring {
+        Expiring(Mutex::new(None))
+    }
+
+    /// Returns the cached credentials, or the ones returned by the given function (along with
+    /// their expiration) if the cached ones are missing or about to expire.
+    fn get<F>(&self, fetch: F) -> Result<Credentials, Error>
+    where
+        F: FnOnce() -> Result<(Credentials, SystemTime), Error>,
+    {
+        let mut cached = self.0.lock().expect("credentials lock has been poisoned");
+        let valid = match *cached {
+            Some((_, expires)) => SystemTime::now() + Expiring::MARGIN < expires,
+            None => false,
+        };
+        if !valid {
+            *cached = Some(fetch()?);
+        }
+        Ok(cached
+            .as_ref()
+            .map(|(credentials, _)| credentials.clone())
+            .expect("credentials have been fetched"))
+    }
+}
+
+/// Credentials for Amazon ECR, which are obtained by exchanging the AWS credentials of the
+/// environment (e.g. the instance's IAM role) for a registry token. Tokens are valid for 12 hours
+/// and are exchanged again shortly before they expire.
+struct Ecr {
+    client: EcrClient,
+    token: Expiring,
+}
+
+impl Ecr {
+    fn new(region: Region) -> Ecr {
+        Ecr {
+            client: EcrClient::new(region),
+            token: Expiring::new(),
+        }
+    }
+
+    fn fetch_token(&self) -> Result<(Credentials, SystemTime), Error> {
+        debug!("fetching ECR authorization token");
+
+        let data = self.client
+            .get_authorization_token(GetAuthorizationTokenRequest::default())
+            .sync()
+            .context("failed to fetch ECR authorization token")?
+            .authorization_data
+            .and_then(|data| data.into_iter().next())
+            .ok_or_else(|| format_err!("ECR didn't return an authorization token"))?;
+
+        let token = data.authorization_token
+            .ok_or_else(|| format_err!("ECR didn't return an authorization token"))?;
+        let credentials = decode_auth(&token).context("malformed ECR authorization token")?;
+
+        let expires = UNIX_EPOCH + Duration::from_secs(data.expires_at.unwrap_or(0.0) as u64);
+        Ok((credentials, expires))
+    }
+}
+
+impl Provider for Ecr {
+    fn credentials(&self, registry: &str) -> Result<Option<Credentials>, Error> {
+        // ECR registries are named <account>.dkr.ecr.<region>.amazonaws.com(.cn).
+        let host = strip_port(registry);
+        if !host.contains(".dkr.ecr.")
+            || !(host.ends_with(".amazonaws.com") || host.ends_with(".amazonaws.com.cn"))
+        {
+            return Ok(None);
+        }
+        self.token.get(|| self.fetch_token()).map(Some)
+    }
+}
+
+/// Fields of a GCP service account key file which are needed to request access tokens.
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// Claims of the JWT with which a service account requests an access token.
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Credentials for Google Container Registry and Artifact Registry, which are OAuth access tokens
+/// of a service account. Tokens are requested by presenting a JWT signed with the account's key,
+/// and are valid for an hour. Each registry needs a token of a different scope.
+struct Gcp {
+    account: ServiceAccount,
+    key: PKey<Private>,
+    http: reqwest::Client,
+    container_token: Expiring,
+    artifact_token: Expiring,
+}
+
+/// Registries hosted by Google Cloud.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GcpRegistry {
+    /// Google Container Registry (gcr.io and its regional hosts)
+    Container,
+    /// Artifact Registry (<region>-docker.pkg.dev)
+    Artifact,
+}
+
+impl GcpRegistry {
+    /// Returns the registry served by the given host (without a port), if any.
+    fn from_host(host: &str) -> Option<GcpRegistry> {
+        if host == "gcr.io" || host.ends_with(".gcr.io") {
+            Some(GcpRegistry::Container)
+        } else if host.ends_with("-docker.pkg.dev") {
+            Some(GcpRegistry::Artifact)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the narrowest scope of access tokens which allows pulling from the registry.
+    /// Container Registry stores its images in Cloud Storage, while Artifact Registry only accepts
+    /// the Cloud Platform scopes.
+    fn scope(self) -> &'static str {
+        match self {
+            GcpRegistry::Container => "https://www.googleapis.com/auth/devstorage.read_only",
+            GcpRegistry::Artifact => "https://www.googleapis.com/auth/cloud-platform.read-only",
+        }
+    }
+}
+
+impl Gcp {
+    /// Lifetime requested for the signed assertions, which is the longest that Google accepts.
+    const LIFETIME: u64 = 3600;
+
+    /// Loads the service account key file (in JSON) at the given path.
+    fn from_file(path: &Path) -> Result<Gcp, Error> {
+        let account: ServiceAccount = serde_json::from_slice(
+            &fs::read(path)
+                .context(format!("failed to read service account from {}", path.display()))?,
+        ).context("failed to parse service account")?;
+        let key = PKey::private_key_from_pem(account.private_key.as_bytes())
+            .context("failed to parse service account key")?;
+        Ok(Gcp {
+            account,
+            key,
+            http: reqwest::Client::new(),
+            container_token: Expiring::new(),
+            artifact_token: Expiring::new(),
+        })
+    }
+
+    /// Signs a JWT asserting the identity of the service account, to be exchanged for a token.
+    fn assertion(&self, scope: &str, now: u64) -> Result<String, Error> {
+        let header = base64url(br#"{"alg":"RS256","typ":"JWT"}"#);
+        let claims = base64url(&serde_json::to_vec(&Claims {
+            iss: &self.account.client_email,
+            scope,
+            aud: &self.account.token_uri,
+            iat: now,
+            exp: now + Gcp::LIFETIME,
+        })?);
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(format!("{}.{}", header, claims).as_bytes())?;
+        let signature = base64url(&signer.sign_to_vec()?);
+        Ok(format!("{}.{}.{}", header, claims, signature))
+    }
+
+    fn fetch_token(&self, scope: &str) -> Result<(Credentials, SystemTime), Error> {
+        debug!(
+            "fetching access token for {} ({})",
+            self.account.client_email, scope
+        );
+
+        let now = SystemTime::now();
+        let assertion = self.assertion(scope, now.duration_since(UNIX_EPOCH)?.as_secs())?;
+        let mut response = self.http
+            .post(&self.account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .context("failed to request GCP access token")?;
+        ensure!(
+            response.status().is_success(),
+            "failed to request GCP access token: {}",
+            response.status()
+        );
+
+        let token: AccessToken = response.json().context("failed to parse GCP access token")?;
+        let credentials = Credentials {
+            username: "oauth2accesstoken".to_string(),
+            password: token.access_token,
+        };
+        Ok((credentials, now + Duration::from_secs(token.expires_in)))
+    }
+}
+
+impl Provider for Gcp {
+    fn credentials(&self, registry: &str) -> Result<Option<Credentials>, Error> {
+        let registry = match GcpRegistry::from_host(strip_port(registry)) {
+            Some(registry) => registry,
+            None => return Ok(None),
+        };
+        let token = match registry {
+            GcpRegistry::Container => &self.container_token,
+            GcpRegistry::Artifact => &self.artifact_token,
+        };
+        token.get(|| self.fetch_token(registry.scope())).map(Some)
+    }
+}
+
+/// Returns the path of the current user's docker configuration, if there is one.
+fn default_docker_config() -> Option<PathBuf> {
+    let dir = match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".docker"),
+    };
+    Some(dir.join("config.json")).filter(|path| path.exists())
+}
+
+/// Fields of a docker configuration file which are relevant to registry credentials.
+#[derive(Deserialize)]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Credentials returned by a docker credential helper.
+#[derive(Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+/// Credentials from a docker configuration file (as written by `docker login`). The credentials of
+/// a registry are either stored in the file itself, or are kept by a credential helper (i.e. a
+/// `docker-credential-<name>` program) named for that registry or for all of them.
+struct DockerConfig {
+    auths: HashMap<String, Credentials>,
+    helpers: HashMap<String, String>,
+    store: Option<String>,
+    helped: Mutex<HashMap<String, (Option<Credentials>, SystemTime)>>,
+}
+
+impl DockerConfig {
+    /// Duration for which the credentials returned by a helper are used before it is run again.
+    const HELPER_TTL: Duration = Duration::from_secs(300);
+
+    /// Names of the files in which Kubernetes mounts the docker configuration of a pull secret.
+    const SECRET_KEYS: [&'static str; 2] = [".dockerconfigjson", ".dockercfg"];
+
+    /// Loads the docker configuration at the given path, in either the current format (as in a
+    /// `kubernetes.io/dockerconfigjson` secret) or the legacy one (as in a
+    /// `kubernetes.io/dockercfg` secret), which only maps each registry to its credentials. If the
+    /// path is a directory (e.g. a mounted secret), the configuration is read from the secret's key
+    /// within it.
+    fn from_file(path: &Path) -> Result<DockerConfig, Error> {
+        let path = if path.is_dir() {
+            DockerConfig::SECRET_KEYS
+                .iter()
+                .map(|key| path.join(key))
+                .find(|path| path.exists())
+                .ok_or_else(|| format_err!("no docker config found in {}", path.display()))?
+        } else {
+            path.to_path_buf()
+        };
+
+        let value: serde_json::Value = serde_json::from_slice(
+            &fs::read(&path)
+                .context(format!("failed to read docker config from {}", path.display()))?,
+        ).context(format!("failed to parse docker config from {}", path.display()))?;
+        let is_current = ["auths", "credHelpers", "credsStore"]
+            .iter()
+            .any(|key| value.get(key).is_some());
+        let file = if is_current {
+            serde_json::from_value(value)
+        } else {
+            serde_json::from_value(value).map(|auths| DockerConfigFile {
+                auths,
+                cred_helpers: HashMap::new(),
+                creds_store: None,
+            })
+        }.context(format!("failed to parse docker config from {}", path.display()))?;
+
+        let mut auths = HashMap::new();
+        for (registry, auth) in file.auths {
+            let credentials = match auth {
+                DockerAuth {
+                    auth: Some(ref auth),
+                    ..
+                } if !auth.is_empty() => decode_auth(auth)
+                    .context(format!("malformed credentials for {}", registry))?,
+                DockerAuth {
+                    username: Some(username),
+                    password: Some(password),
+                    ..
+                } => Credentials { username, password },
+                _ => continue,
+            };
+            auths.insert(docker_registry_host(&registry), credentials);
+        }
+
+        Ok(DockerConfig {
+            auths,
+            helpers: file.cred_helpers
+                .into_iter()
+                .map(|(registry, helper)| (docker_registry_host(&registry), helper))
+                .collect(),
+            store: file.creds_store,
+            helped: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the credentials kept by the given helper for the given registry, running the helper
+    /// only if it hasn't been run for that registry recently.
+    fn helper_credentials(
+        &self,
+        helper: &str,
+        registry: &str,
+    ) -> Result<Option<Credentials>, Error> {
+        let mut helped = self.helped.lock().expect("helper lock has been poisoned");
+        if let Some(&(ref credentials, expires)) = helped.get(registry) {
+            if SystemTime::now() < expires {
+                return Ok(credentials.clone());
+            }
+        }
+
+        let credentials = run_helper(helper, registry)
+            .context(format!("failed to run credential helper {}", helper))?;
+        helped.insert(
+            registry.to_string(),
+            (credentials.clone(), SystemTime::now() + DockerConfig::HELPER_TTL),
+        );
+        Ok(credentials)
+    }
+}
+
+impl Provider for DockerConfig {
+    fn credentials(&self, registry: &str) -> Result<Option<Credentials>, Error> {
+        let registry = docker_registry_host(registry);
+        if let Some(helper) = self.helpers.get(&registry) {
+            return self.helper_credentials(helper, &registry);
+        }
+        if let Some(credentials) = self.auths.get(&registry) {
+            return Ok(Some(credentials.clone()));
+        }
+        match self.store {
+            Some(ref store) => self.helper_credentials(store, &registry),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Normalizes the given registry (as keyed in a docker configuration file, possibly as a URL) to
+/// its host. Docker Hub is keyed by its index, rather than by the host of its registry.
+fn docker_registry_host(registry: &str) -> String {
+    let host = registry
+        .trim_left_matches("https://")
+        .trim_left_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "docker.io" | "registry-1.docker.io" => "index.docker.io".to_string(),
+        _ => host.to_string(),
+    }
+}
+
+/// Runs `docker-credential-<helper> get` for the given registry. Helpers report registries for
+/// which they have no credentials as an error, which is indistinguishable from other errors, so
+/// any failure to run the helper is treated as having no credentials.
+fn run_helper(helper: &str, registry: &str) -> Result<Option<Credentials>, Error> {
+    trace!("running credential helper {} for {}", helper, registry);
+
+    let program = format!("docker-credential-{}", helper);
+    let output = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            child
+                .stdin
+                .take()
+                .expect("stdin has been piped")
+                .write_all(registry.as_bytes())?;
+            child.wait_with_output()
+        });
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("Failed to run {} for {}: {}", program, registry, err);
+            return Ok(None);
+        }
+    };
+    if !output.status.success() {
+        warn!(
+            "Credential helper {} has no credentials for {}: {}",
+            helper,
+            registry,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+        return Ok(None);
+    }
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+    Ok(Some(Credentials {
+        username: credentials.username,
+        password: credentials.secret,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use std::sync::{Once, ONCE_INIT};
+
+    /// Returns a new, empty directory for the given test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("graph-builder-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(name: &str, contents: &str) -> DockerConfig {
+        let dir = test_dir(name);
+        let path = dir.join("config.json");
+        fs::write(&path, contents).unwrap();
+        let config = DockerConfig::from_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    fn username(config: &DockerConfig, registry: &str) -> Option<String> {
+        config
+            .credentials(registry)
+            .unwrap()
+            .map(|credentials| credentials.username)
+    }
+
+    /// Installs the credential helpers `gb-registry` and `gb-store` on the path, which return
+    /// their own name as the username.
+    fn install_helpers() {
+        static INSTALL: Once = ONCE_INIT;
+        INSTALL.call_once(|| {
+            let dir = test_dir("helpers");
+            for name in &["gb-registry", "gb-store"] {
+                let path = dir.join(format!("docker-credential-{}", name));
+                let script = format!(
+                    "#!/bin/sh\nread registry\necho '{}'\n",
+                    json!({"Username": name, "Secret": "secret"})
+                );
+                fs::write(&path, script).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let path = env::var_os("PATH").unwrap_or_default();
+            let paths = Some(dir).into_iter().chain(env::split_paths(&path));
+            env::set_var("PATH", env::join_paths(paths).unwrap());
+        });
+    }
+
+    #[test]
+    fn read_current_config() {
+        let config = config(
+            "current-config",
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": {"auth": "aHViOnNlY3JldA=="},
+                    "quay.io": {"username": "quay", "password": "secret"},
+                    "registry.example.com": {"auth": ""}
+                }
+            }"#,
+        );
+        assert_eq!(username(&config, "registry-1.docker.io").unwrap(), "hub");
+        assert_eq!(username(&config, "quay.io").unwrap(), "quay");
+        assert!(username(&config, "registry.example.com").is_none());
+    }
+
+    #[test]
+    fn prefer_cred_helpers() {
+        install_helpers();
+        let config = config(
+            "cred-helpers",
+            r#"{
+                "auths": {
+                    "quay.io": {"username": "quay", "password": "secret"},
+                    "registry.example.com": {"username": "example", "password": "secret"}
+                },
+                "credHelpers": {"quay.io": "gb-registry"},
+                "credsStore": "gb-store"
+            }"#,
+        );
+        assert_eq!(username(&config, "quay.io").unwrap(), "gb-registry");
+        assert_eq!(username(&config, "registry.example.com").unwrap(), "example");
+        assert_eq!(username(&config, "docker.io").unwrap(), "gb-store");
+    }
+
+    #[test]
+    fn ignore_missing_helpers() {
+        let config = config("missing-helper", r#"{"credsStore": "gb-missing"}"#);
+        assert!(username(&config, "quay.io").is_none());
+    }
+
+    #[test]
+    fn read_legacy_config() {
+        let config = config(
+            "legacy-config",
+            r#"{"quay.io": {"username": "quay", "password": "secret", "email": "a@b.c"}}"#,
+        );
+        assert_eq!(username(&config, "quay.io").unwrap(), "quay");
+    }
+
+    #[test]
+    fn read_secret_dirs() {
+        let credentials = r#"{"quay.io": {"username": "quay", "password": "secret"}}"#;
+        for (key, contents) in &[
+            (".dockerconfigjson", format!(r#"{{"auths": {}}}"#, credentials)),
+            (".dockercfg", credentials.to_string()),
+        ] {
+            let dir = test_dir("secret-dir");
+            fs::write(dir.join(key), contents).unwrap();
+            let config = DockerConfig::from_file(&dir).unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(username(&config, "quay.io").unwrap(), "quay");
+        }
+
+        let dir = test_dir("empty-secret-dir");
+        assert!(DockerConfig::from_file(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn match_gcp_registries() {
+        assert_eq!(GcpRegistry::from_host("gcr.io"), Some(GcpRegistry::Container));
+        assert_eq!(GcpRegistry::from_host("eu.gcr.io"), Some(GcpRegistry::Container));
+        assert_eq!(
+            GcpRegistry::from_host("europe-west1-docker.pkg.dev"),
+            Some(GcpRegistry::Artifact)
+        );
+        assert_eq!(GcpRegistry::from_host("quay.io"), None);
+        assert_eq!(GcpRegistry::from_host("gcr.io.example.com"), None);
+        assert_eq!(GcpRegistry::from_host("docker.pkg.dev"), None);
+
+        assert!(GcpRegistry::Container.scope().ends_with("/devstorage.read_only"));
+        assert!(GcpRegistry::Artifact.scope().ends_with("/cloud-platform.read-only"));
+    }
+}
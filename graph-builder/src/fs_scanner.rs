@@ -0,0 +1,54 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use failure::{Error, ResultExt};
+use registry::{self, Release};
+use serde_json;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::Path;
+
+/// Reads all of the release metadata documents from the given directory. Each document is named
+/// after the tag of its release image (e.g. `1.0.0.json`), which is combined with the given
+/// registry and repository to form the payload of the release.
+pub fn fetch_releases(dir: &Path, registry: &str, repo: &str) -> Result<Vec<Release>, Error> {
+    let mut paths = fs::read_dir(dir)
+        .context(format!("failed to read directory {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let mut releases = Vec::new();
+    for path in paths {
+        let tag = match (path.extension(), path.file_stem()) {
+            (Some(ext), Some(stem)) if ext == OsStr::new("json") => stem.to_string_lossy(),
+            _ => {
+                debug!("skipping {}", path.display());
+                continue;
+            }
+        };
+
+        trace!("reading metadata from {}", path.display());
+        let file = File::open(&path).context(format!("failed to open {}", path.display()))?;
+        releases.push(Release {
+            source: registry::payload(registry, repo, &tag),
+            arch: None,
+            created: None,
+            digest: None,
+            metadata: serde_json::from_reader(file)
+                .context(format!("failed to parse {}", path.display()))?,
+        });
+    }
+    Ok(releases)
+}
@@ -13,66 +13,1109 @@
 // limitations under the License.
 
 use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{ContentEncoding, HeaderMap};
 use actix_web::{HttpMessage, HttpRequest, HttpResponse};
-use cincinnati::{AbstractRelease, CONTENT_TYPE_GRAPH_V1, Graph, Release};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use cache::Cache;
+use chrono::{DateTime, Utc};
+use cincinnati::{AbstractRelease, CONTENT_TYPE_GRAPH_V1, CONTENT_TYPE_GRAPH_V2, Graph, Release};
 use config;
+use credentials::{self, Provider};
 use failure::{Error, ResultExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use fs_scanner;
+use graph_data::{self, GraphData};
+use openssl::sha::sha256;
+use otlp;
+use prometheus::{Histogram, IntCounter, IntGauge, IntGaugeVec};
+use rand::{self, Rng};
 use registry;
+use release::MetadataKind;
+use rusoto_core::Region;
+use s3_scanner;
+use semver::Version;
+use serde_cbor;
 use serde_json;
+use signing::{Signer, SIGNATURE_HEADER};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+lazy_static! {
+    static ref SCAN_DURATION: Histogram = register_histogram!(
+        "graph_builder_scan_duration_seconds",
+        "Time taken to scan the release source and build the graphs"
+    ).unwrap();
+    static ref SCAN_FAILURES: IntCounter = register_int_counter!(
+        "graph_builder_scan_failures_total",
+        "Number of scans which failed to produce a graph"
+    ).unwrap();
+    static ref SCANS_UNCHANGED: IntCounter = register_int_counter!(
+        "graph_builder_scans_unchanged_total",
+        "Number of scans which produced the same graphs as the previous one"
+    ).unwrap();
+    static ref STALE: IntGauge = register_int_gauge!(
+        "graph_builder_graph_stale",
+        "Whether the served graphs are older than the configured maximum age"
+    ).unwrap();
+    static ref LAST_UPDATE: IntGauge = register_int_gauge!(
+        "graph_builder_graph_last_update_timestamp_seconds",
+        "Time at which the served graphs were last updated"
+    ).unwrap();
+    static ref RELEASES: IntGauge = register_int_gauge!(
+        "graph_builder_releases",
+        "Number of releases found during the last successful scan"
+    ).unwrap();
+    static ref GRAPH_NODES: IntGaugeVec = register_int_gauge_vec!(
+        "graph_builder_graph_nodes",
+        "Number of nodes in the graph of each architecture",
+        &["arch"]
+    ).unwrap();
+    static ref GRAPH_EDGES: IntGaugeVec = register_int_gauge_vec!(
+        "graph_builder_graph_edges",
+        "Number of edges in the graph of each architecture",
+        &["arch"]
+    ).unwrap();
+}
+
+/// Media type of the CBOR encoding of the graph.
+const CONTENT_TYPE_CBOR: &str = "application/cbor";
+
+/// Warning sent with graphs which are older than the configured maximum age (RFC 7234, 5.5.1).
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// Encodings in which the graph is served according to the v1 schema.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Json,
+    Gzip,
+    Cbor,
+}
+
+impl Encoding {
+    /// Chooses the encoding requested by the given headers, if the client accepts any of them.
+    /// Only the JSON encoding is compressed, since the CBOR encoding is already compact.
+    fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+        match headers.get(header::ACCEPT) {
+            Some(entry) if entry == HeaderValue::from_static(CONTENT_TYPE_GRAPH_V1) => {
+                if accepts_gzip(headers) {
+                    Some(Encoding::Gzip)
+                } else {
+                    Some(Encoding::Json)
+                }
+            }
+            Some(entry) if entry == HeaderValue::from_static(CONTENT_TYPE_CBOR) => {
+                Some(Encoding::Cbor)
+            }
+            _ => None,
+        }
+    }
+}
 
 pub fn index(req: HttpRequest<State>) -> HttpResponse {
+    let encoding = match Encoding::negotiate(req.headers()) {
+        Some(encoding) => encoding,
+        None => return HttpResponse::NotAcceptable().finish(),
+    };
+
+    let state = req.state();
+    let arch = query_param(&req, "arch").unwrap_or_else(|| state.default_arch.clone());
+    let channel = query_param(&req, "channel");
+    let served = state.served.load();
+    let document = match served.documents.get(&(arch, channel)) {
+        Some(document) => document.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    // Each encoding of the graph is a separate representation, with its own entity tag.
+    let etag = format!(
+        "\"{}{}\"",
+        document.digest,
+        match encoding {
+            Encoding::Json => "",
+            Encoding::Gzip => "-gzip",
+            Encoding::Cbor => "-cbor",
+        }
+    );
+
+    if etag_matches(req.headers(), &etag) {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::VARY, "Accept, Accept-Encoding")
+            .finish();
+    }
+
+    // The bodies are served exactly as they were encoded (and signed), so the server must not
+    // compress them again.
+    let mut response = HttpResponse::Ok();
+    response
+        .header(header::ETAG, etag)
+        .header(header::VARY, "Accept, Accept-Encoding")
+        .content_encoding(ContentEncoding::Identity);
+    if let Some(modified) = last_modified(&served) {
+        response.header(header::LAST_MODIFIED, modified);
+    }
+    if state.is_stale(&served) {
+        response.header(header::WARNING, STALE_WARNING);
+    }
+    if let Some(ref signatures) = document.signatures {
+        let signature = match encoding {
+            Encoding::Json => &signatures.json,
+            Encoding::Gzip => &signatures.gzip,
+            Encoding::Cbor => &signatures.cbor,
+        };
+        response.header(SIGNATURE_HEADER, signature.as_str());
+    }
+    match encoding {
+        Encoding::Json => response
+            .content_type(CONTENT_TYPE_GRAPH_V1)
+            .body(document.json),
+        Encoding::Gzip => response
+            .content_type(CONTENT_TYPE_GRAPH_V1)
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(document.gzip),
+        Encoding::Cbor => response.content_type(CONTENT_TYPE_CBOR).body(document.cbor),
+    }
+}
+
+/// Serves the graph according to the v2 schema, which includes the conditional edges.
+pub fn index_v2(req: HttpRequest<State>) -> HttpResponse {
     match req.headers().get(header::ACCEPT) {
-        Some(entry) if entry == HeaderValue::from_static(CONTENT_TYPE_GRAPH_V1) => {
-            HttpResponse::Ok().content_type(CONTENT_TYPE_GRAPH_V1).body(
-                req.state()
-                    .json
-                    .read()
-                    .expect("json lock has been poisoned")
-                    .clone(),
-            )
+        Some(entry) if entry == HeaderValue::from_static(CONTENT_TYPE_GRAPH_V2) => {}
+        _ => return HttpResponse::NotAcceptable().finish(),
+    }
+
+    let state = req.state();
+    let arch = query_param(&req, "arch").unwrap_or_else(|| state.default_arch.clone());
+    let channel = query_param(&req, "channel");
+    let served = state.served.load();
+    let document = match served.documents.get(&(arch, channel)) {
+        Some(document) => document.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let etag = format!("\"{}-v2\"", document.digest_v2);
+    if etag_matches(req.headers(), &etag) {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::VARY, "Accept")
+            .finish();
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
+        .header(header::ETAG, etag)
+        .header(header::VARY, "Accept")
+        .content_encoding(ContentEncoding::Identity);
+    if let Some(modified) = last_modified(&served) {
+        response.header(header::LAST_MODIFIED, modified);
+    }
+    if state.is_stale(&served) {
+        response.header(header::WARNING, STALE_WARNING);
+    }
+    if let Some(ref signatures) = document.signatures {
+        response.header(SIGNATURE_HEADER, signatures.v2.as_str());
+    }
+    response
+        .content_type(CONTENT_TYPE_GRAPH_V2)
+        .body(document.v2)
+}
+
+/// Formats the time at which the served graphs were last updated as an HTTP date.
+fn last_modified(served: &Served) -> Option<String> {
+    served.modified.map(|modified| {
+        DateTime::<Utc>::from(modified)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    })
+}
+
+/// Checks whether any of the entity tags listed in the request's If-None-Match header match the
+/// given one.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    match headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) => value
+            .split(',')
+            .map(|tag| tag.trim().trim_left_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag),
+        None => false,
+    }
+}
+
+/// Lists the releases to which the given version can be updated directly.
+pub fn updates(req: HttpRequest<State>) -> HttpResponse {
+    let version = match query_param(&req, "version").map(|version| Version::parse(&version)) {
+        Some(Ok(version)) => version,
+        _ => return HttpResponse::BadRequest().finish(),
+    };
+
+    let state = req.state();
+    let arch = query_param(&req, "arch").unwrap_or_else(|| state.default_arch.clone());
+    let channel = query_param(&req, "channel");
+    let served = state.served.load();
+    let graph = match served.graphs.get(&arch) {
+        Some(graph) => graph,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let current = match graph.find_by_version(&version) {
+        Some(current) => current,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let updates: Vec<&Release> = graph
+        .next_releases(&current)
+        .filter(|release| match channel {
+            Some(ref channel) => in_channel(release, &state.channel_key, channel),
+            None => true,
+        })
+        .collect();
+    HttpResponse::Ok().json(updates)
+}
+
+#[derive(Serialize)]
+struct Stats {
+    releases: usize,
+    transitions: usize,
+    oldest: Option<Version>,
+    newest: Option<Version>,
+    orphans: usize,
+    channels: Vec<String>,
+}
+
+/// Summarizes the graph of the requested architecture, for quick operational checks.
+pub fn stats(req: HttpRequest<State>) -> HttpResponse {
+    let state = req.state();
+    let arch = query_param(&req, "arch").unwrap_or_else(|| state.default_arch.clone());
+    let served = state.served.load();
+    let graph = match served.graphs.get(&arch) {
+        Some(graph) => graph,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let stats = graph.stats();
+    let mut channels: Vec<_> = channels(graph, &state.channel_key).into_iter().collect();
+    channels.sort();
+    HttpResponse::Ok().json(Stats {
+        releases: stats.releases,
+        transitions: stats.transitions,
+        oldest: stats.oldest,
+        newest: stats.newest,
+        orphans: stats.orphans,
+        channels,
+    })
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    scan_started: Option<String>,
+    scan_finished: Option<String>,
+    scan_error: Option<String>,
+    releases: HashMap<String, usize>,
+    cache_entries: usize,
+}
+
+/// Summarizes the state of the service: the outcome of the most recent scan (which is still
+/// running if it started after the last one finished), the number of releases in the graph of
+/// each architecture, and the number of images in the metadata cache.
+pub fn status(req: HttpRequest<State>) -> HttpResponse {
+    let state = req.state();
+    let scan = state.scan.read().expect("scan lock has been poisoned").clone();
+    let rfc3339 = |time: SystemTime| DateTime::<Utc>::from(time).to_rfc3339();
+    let releases = state
+        .served
+        .load()
+        .graphs
+        .iter()
+        .map(|(arch, graph)| (arch.clone(), graph.release_count()))
+        .collect();
+    let cache_entries = state.cache.len();
+    HttpResponse::Ok().json(Status {
+        version: env!("CARGO_PKG_VERSION"),
+        scan_started: scan.started.map(rfc3339),
+        scan_finished: scan.finished.map(rfc3339),
+        scan_error: scan.error,
+        releases,
+        cache_entries,
+    })
+}
+
+#[derive(Serialize)]
+struct ScanStatusResponse {
+    running: bool,
+    elapsed_seconds: Option<u64>,
+    #[serde(flatten)]
+    progress: registry::ScanProgress,
+}
+
+/// Reports the progress of the running scan or, if none is running, of the last one. The elapsed
+/// time is that of the scan so far, or the total duration of the last one.
+pub fn scan_status(req: HttpRequest<State>) -> HttpResponse {
+    let scan = req.state()
+        .scan
+        .read()
+        .expect("scan lock has been poisoned")
+        .clone();
+    let running = match (scan.started, scan.finished) {
+        (Some(started), Some(finished)) => started > finished,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    let elapsed = match (scan.started, scan.finished) {
+        (Some(started), Some(finished)) if !running => finished.duration_since(started).ok(),
+        (Some(started), _) => started.elapsed().ok(),
+        (None, _) => None,
+    };
+    HttpResponse::Ok().json(ScanStatusResponse {
+        running,
+        elapsed_seconds: elapsed.map(|elapsed| elapsed.as_secs()),
+        progress: registry::progress(),
+    })
+}
+
+/// Checks whether the client accepts gzip-encoded responses.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    match headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) => value.split(',').any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params.next() == Some("gzip") && params.all(|param| {
+                param
+                    .trim_left_matches("q=")
+                    .parse::<f32>()
+                    .map(|q| q > 0.0)
+                    .unwrap_or(true)
+            })
+        }),
+        None => false,
+    }
+}
+
+fn query_param<S>(req: &HttpRequest<S>, key: &str) -> Option<String> {
+    form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// A graph serialized as both JSON and CBOR, as well as JSON following the v2 schema, along with
+/// the SHA-256 digests of both JSON documents, from which the entity tags are derived. The JSON is
+/// also compressed up front, so that it isn't compressed again for every request. The encodings
+/// are reference-counted, so that they can be handed to the server without being copied.
+#[derive(Clone)]
+struct Document {
+    json: Bytes,
+    gzip: Bytes,
+    cbor: Bytes,
+    v2: Bytes,
+    digest: String,
+    digest_v2: String,
+    signatures: Option<Signatures>,
+}
+
+/// Detached signatures of each of the encodings of a document.
+#[derive(Clone)]
+struct Signatures {
+    json: String,
+    gzip: String,
+    cbor: String,
+    v2: String,
+}
+
+impl Document {
+    fn serialize(graph: &Graph, signer: Option<&Signer>) -> Result<Document, Error> {
+        let mut document = Document::new(
+            serde_json::to_string(graph)?,
+            serde_json::to_string(&graph.v2())?,
+            serde_cbor::to_vec(graph)?,
+        );
+        if let Some(signer) = signer {
+            document.signatures = Some(Signatures {
+                json: signer.sign(&document.json)?,
+                gzip: signer.sign(&document.gzip)?,
+                cbor: signer.sign(&document.cbor)?,
+                v2: signer.sign(&document.v2)?,
+            });
+        }
+        Ok(document)
+    }
+
+    fn new(json: String, v2: String, cbor: Vec<u8>) -> Document {
+        let (digest, digest_v2) = (hex_sha256(&json), hex_sha256(&v2));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        let gzip = encoder
+            .write_all(json.as_bytes())
+            .and_then(|_| encoder.finish())
+            .expect("failed to compress graph");
+
+        Document {
+            json: Bytes::from(json),
+            gzip: Bytes::from(gzip),
+            cbor: Bytes::from(cbor),
+            v2: Bytes::from(v2),
+            digest,
+            digest_v2,
+            signatures: None,
         }
-        _ => HttpResponse::NotAcceptable().finish(),
     }
 }
 
+/// Returns the hex encoding of the SHA-256 digest of the given document.
+fn hex_sha256(document: &str) -> String {
+    sha256(document.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Serialized graphs, keyed by architecture and channel. The complete graph of each architecture
+/// has no channel.
+type Documents = HashMap<(String, Option<String>), Document>;
+
+/// Graphs served to clients, keyed by architecture, along with their documents and the time at
+/// which they were produced. These are replaced as a whole after each scan, so that requests never
+/// wait on a lock to serve them.
+#[derive(Default)]
+struct Served {
+    graphs: HashMap<String, Graph>,
+    documents: Documents,
+    modified: Option<SystemTime>,
+}
+
+/// Times at which the most recent scan started and finished, and the error which failed it.
+#[derive(Clone, Default)]
+struct ScanStatus {
+    started: Option<SystemTime>,
+    finished: Option<SystemTime>,
+    error: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct State {
-    json: Arc<RwLock<String>>,
+    served: Arc<ArcSwap<Served>>,
+    scan: Arc<RwLock<ScanStatus>>,
+    /// Time (in seconds since the epoch) at which the last successful scan finished, or zero if
+    /// there hasn't been one. This is kept apart from the scan status so that checking whether the
+    /// served graphs are stale doesn't take a lock on every request.
+    confirmed: Arc<AtomicUsize>,
+    default_arch: String,
+    channel_key: String,
+    cache: Arc<Cache>,
+    signer: Option<Arc<Signer>>,
+    stale_after: Option<Duration>,
 }
 
 impl State {
-    pub fn new() -> State {
+    pub fn new(default_arch: String, channel_key: String, cache: Cache) -> State {
         State {
-            json: Arc::new(RwLock::new(String::new())),
+            served: Arc::new(ArcSwap::from(Arc::new(Served::default()))),
+            scan: Arc::new(RwLock::new(ScanStatus::default())),
+            confirmed: Arc::new(AtomicUsize::new(0)),
+            default_arch,
+            channel_key,
+            cache: Arc::new(cache),
+            signer: None,
+            stale_after: None,
+        }
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Sets the key with which the served documents are signed.
+    pub fn signer(mut self, signer: Option<Signer>) -> State {
+        self.signer = signer.map(Arc::new);
+        self
+    }
+
+    /// Sets the age after which the served graphs are considered stale, if they haven't been
+    /// confirmed by a successful scan since.
+    pub fn stale_after(mut self, age: Option<Duration>) -> State {
+        self.stale_after = age;
+        self
+    }
+
+    /// Checks whether the given documents are older than the maximum age. Their age is counted from
+    /// the last successful scan or, if there hasn't been one, from when they were produced.
+    fn is_stale(&self, served: &Served) -> bool {
+        let max_age = match self.stale_after {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+        let scanned = match self.confirmed.load(Ordering::SeqCst) {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+        };
+        match scanned.or(served.modified) {
+            Some(time) => time.elapsed().map(|age| age > max_age).unwrap_or(false),
+            None => false,
         }
     }
+
+    fn serialize(&self, graphs: &HashMap<String, Graph>) -> Result<Documents, Error> {
+        serialize(graphs, &self.channel_key, self.signer.as_ref().map(|signer| &**signer))
+    }
+
+    /// Loads the graphs saved by a previous run, so that they can be served until the first scan
+    /// completes.
+    pub fn restore(&self, path: &Path) -> Result<(), Error> {
+        let json = fs::read_to_string(path).context("failed to read saved graphs")?;
+        let graphs: HashMap<String, Graph> =
+            serde_json::from_str(&json).context("failed to parse saved graphs")?;
+        let documents = self.serialize(&graphs)?;
+        self.update(documents, graphs, fs::metadata(path)?.modified()?);
+        Ok(())
+    }
+
+    /// Loads a pre-rendered graph, which is served as the graph of the default architecture.
+    fn load(&self, path: &Path, modified: SystemTime) -> Result<(), Error> {
+        let json = fs::read_to_string(path).context("failed to read graph")?;
+        let graph: Graph = serde_json::from_str(&json).context("failed to parse graph")?;
+        let mut graphs = HashMap::new();
+        graphs.insert(self.default_arch.clone(), graph);
+        let documents = self.serialize(&graphs)?;
+        record_graphs(&graphs);
+        self.update(documents, graphs, modified);
+        Ok(())
+    }
+
+    /// Replaces the served graphs, recording the time at which they were produced.
+    fn update(&self, documents: Documents, graphs: HashMap<String, Graph>, modified: SystemTime) {
+        self.served.store(Arc::new(Served {
+            graphs,
+            documents,
+            modified: Some(modified),
+        }));
+        LAST_UPDATE.set(
+            modified
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs() as i64)
+                .unwrap_or(0),
+        );
+    }
 }
 
-pub fn run(opts: &config::Options, state: &State) -> ! {
+/// Scans for releases once per period, forever. Once the reload flag is set (on SIGHUP), the
+/// options are loaded again before the next scan. Only the options governing the scan take effect
+/// this way; the server, its cache, and the options of its handlers keep their initial settings.
+pub fn run(
+    mut opts: config::Options,
+    mut credentials: Option<Arc<Provider>>,
+    state: &State,
+    reload: &AtomicBool,
+) -> ! {
+    let mut failures = 0;
+    let mut fingerprint = None;
     loop {
+        if reload.swap(false, Ordering::SeqCst) {
+            let reloaded = config::Options::load()
+                .and_then(|opts| Ok((credentials::provider(&opts)?, opts)));
+            match reloaded {
+                Ok((provider, reloaded)) => {
+                    info!("Reloaded configuration");
+                    opts = reloaded;
+                    credentials = provider;
+                }
+                Err(err) => error!("Failed to reload configuration: {}", err),
+            }
+        }
+
         debug!("Updating graph...");
-        match create_graph(&opts) {
-            Ok(graph) => match serde_json::to_string(&graph) {
-                Ok(json) => *state.json.write().expect("json lock has been poisoned") = json,
-                Err(err) => error!("Failed to serialize graph: {}", err),
+        state.scan.write().expect("scan lock has been poisoned").started = Some(SystemTime::now());
+        let timer = SCAN_DURATION.start_timer();
+        let mut span = otlp::Span::enter("scan");
+        let result = create_graphs(&opts, &state.cache, credentials.as_ref()).and_then(|graphs| {
+            if let Some(max_loss) = opts.max_release_loss {
+                let served = concrete_count(&state.served.load().graphs);
+                check_release_loss(served, concrete_count(&graphs), max_loss)?;
+            }
+            Ok(graphs)
+        });
+        if let Err(ref err) = result {
+            span.fail(err);
+        }
+        drop(span);
+        timer.observe_duration();
+        let new_fingerprint = result.as_ref().ok().and_then(graphs_fingerprint);
+        let outcome = match result {
+            Ok(_) if new_fingerprint.is_some() && new_fingerprint == fingerprint => {
+                debug!("Graphs are unchanged");
+                SCANS_UNCHANGED.inc();
+                Ok(())
+            }
+            Ok(graphs) => match state.serialize(&graphs) {
+                Ok(json) => {
+                    fingerprint = new_fingerprint;
+                    if let Some(ref path) = opts.state_file {
+                        if let Err(err) = save(path, &graphs) {
+                            error!("Failed to save graphs to {}: {}", path.display(), err)
+                        }
+                    }
+                    record_graphs(&graphs);
+                    log_changes(&state.served.load().graphs, &graphs);
+                    state.update(json, graphs, SystemTime::now());
+                    Ok(())
+                }
+                Err(err) => {
+                    SCAN_FAILURES.inc();
+                    error!("Failed to serialize graph: {}", err);
+                    Err(format!("failed to serialize graph: {}", err))
+                }
             },
-            Err(err) => err.causes().for_each(|cause| error!("{}", cause)),
+            Err(err) => {
+                SCAN_FAILURES.inc();
+                err.causes().for_each(|cause| error!("{}", cause));
+                Err(err.to_string())
+            }
+        };
+        failures = if outcome.is_ok() { 0 } else { failures + 1 };
+        let finished = SystemTime::now();
+        if outcome.is_ok() {
+            let secs = finished.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs());
+            state.confirmed.store(secs.unwrap_or(0) as usize, Ordering::SeqCst);
+        }
+        {
+            let mut scan = state.scan.write().expect("scan lock has been poisoned");
+            scan.finished = Some(finished);
+            scan.error = outcome.err();
+        }
+        let stale = state.is_stale(&state.served.load());
+        if stale && failures > 0 {
+            warn!("The served graphs are stale; the last successful scan was too long ago");
         }
-        thread::sleep(opts.period);
+        STALE.set(stale as i64);
+        thread::sleep(scan_delay(&opts, failures));
     }
 }
 
-fn create_graph(opts: &config::Options) -> Result<Graph, Error> {
+/// Hashes the given graphs, including the metadata and conditional edges of their releases, so that
+/// scans which produce the same graphs can be detected. Returns `None` if a graph can't be
+/// serialized.
+fn graphs_fingerprint(graphs: &HashMap<String, Graph>) -> Option<u64> {
+    let mut archs: Vec<_> = graphs.keys().collect();
+    archs.sort();
+
+    let mut hasher = DefaultHasher::new();
+    for arch in archs {
+        arch.hash(&mut hasher);
+        // Serializing through a JSON value sorts the keys of the metadata maps.
+        serde_json::to_value(graphs[arch].v2())
+            .ok()?
+            .to_string()
+            .hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+/// Serves the pre-rendered graph at the given path instead of scanning for releases. The file is
+/// checked for changes once per period, and loaded again whenever it has been modified.
+pub fn serve_from(path: &Path, period: Duration, state: &State) -> ! {
+    let mut loaded = None;
+    loop {
+        match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) if Some(modified) != loaded => match state.load(path, modified) {
+                Ok(()) => {
+                    info!("Loaded graph from {}", path.display());
+                    loaded = Some(modified);
+                }
+                Err(err) => error!("Failed to load graph from {}: {}", path.display(), err),
+            },
+            Ok(_) => {}
+            Err(err) => error!("Failed to read {}: {}", path.display(), err),
+        }
+        STALE.set(state.is_stale(&state.served.load()) as i64);
+        thread::sleep(period);
+    }
+}
+
+/// Scans for releases once and writes the graph of the default architecture, either to the
+/// configured output file or to stdout.
+pub fn render(
+    opts: &config::Options,
+    cache: &Cache,
+    credentials: Option<&Arc<Provider>>,
+) -> Result<(), Error> {
+    let graphs = create_graphs(opts, cache, credentials)?;
+    let json = serde_json::to_string(&graphs[&opts.default_arch])?;
+    match opts.output {
+        Some(ref path) => fs::write(path, json)
+            .context(format!("failed to write graph to {}", path.display()))?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// Checks that a scan which found the given number of releases hasn't lost more than the given
+/// percentage of the releases being served. A scan which only saw some of the releases (e.g.
+/// because the registry transiently listed no tags) is rejected this way, so that the served
+/// graphs are kept for as long as the loss persists. A scan which found no releases at all is
+/// always rejected while any are being served.
+fn check_release_loss(served: usize, found: usize, max_loss: u8) -> Result<(), Error> {
+    if found >= served {
+        return Ok(());
+    }
+    ensure!(
+        found > 0,
+        "scan found no releases, but {} are being served; keeping the served graphs",
+        served
+    );
+
+    let loss = (served - found) * 100 / served;
+    ensure!(
+        loss <= usize::from(max_loss),
+        "scan found {} releases, {}% fewer than the {} being served; keeping the served graphs",
+        found,
+        loss,
+        served
+    );
+    Ok(())
+}
+
+/// Returns the number of concrete releases in the given graphs, leaving out the abstract ones which
+/// are only referred to by other releases.
+fn concrete_count(graphs: &HashMap<String, Graph>) -> usize {
+    graphs
+        .values()
+        .flat_map(Graph::releases)
+        .filter(|release| match release {
+            Release::Concrete(_) => true,
+            Release::Abstract(_) => false,
+        })
+        .count()
+}
+
+/// Returns the pause before the next scan. After consecutive failed scans, the period is doubled
+/// for each failure (up to the maximum backoff, if one is set). A random jitter of up to the
+/// configured amount is then added, so that replicas don't scan in lockstep.
+fn scan_delay(opts: &config::Options, failures: u32) -> Duration {
+    let mut delay = opts.period;
+    if let Some(max_backoff) = opts.max_backoff {
+        let backoff = opts.period
+            .checked_mul(1 << cmp::min(failures, 16))
+            .unwrap_or(max_backoff);
+        delay = cmp::max(delay, cmp::min(backoff, max_backoff));
+    }
+    if let Some(jitter) = opts.period_jitter {
+        let millis = jitter.as_secs() * 1000 + u64::from(jitter.subsec_millis());
+        delay += Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1));
+    }
+    delay
+}
+
+/// Updates the metrics describing the given graphs.
+fn record_graphs(graphs: &HashMap<String, Graph>) {
+    GRAPH_NODES.reset();
+    GRAPH_EDGES.reset();
+    for (arch, graph) in graphs {
+        GRAPH_NODES
+            .with_label_values(&[arch])
+            .set(graph.release_count() as i64);
+        GRAPH_EDGES
+            .with_label_values(&[arch])
+            .set(graph.transition_count() as i64);
+    }
+}
+
+/// Logs the releases and updates which were added to or removed from each of the graphs since the
+/// previous scan.
+fn log_changes(previous: &HashMap<String, Graph>, graphs: &HashMap<String, Graph>) {
+    let empty = Graph::default();
+    for (arch, graph) in graphs {
+        let diff = previous.get(arch).unwrap_or(&empty).diff(graph);
+        if diff.is_empty() {
+            continue;
+        }
+
+        info!(
+            "Graph for {} changed: {} releases added, {} removed; {} updates added, {} removed",
+            arch,
+            diff.added_releases.len(),
+            diff.removed_releases.len(),
+            diff.added_transitions.len(),
+            diff.removed_transitions.len()
+        );
+        diff.added_releases
+            .iter()
+            .for_each(|version| debug!("{}: added release {}", arch, version));
+        diff.removed_releases
+            .iter()
+            .for_each(|version| debug!("{}: removed release {}", arch, version));
+        diff.added_transitions
+            .iter()
+            .for_each(|(from, to)| debug!("{}: added update {} -> {}", arch, from, to));
+        diff.removed_transitions
+            .iter()
+            .for_each(|(from, to)| debug!("{}: removed update {} -> {}", arch, from, to));
+    }
+}
+
+/// Serializes each of the given graphs, keyed by architecture, along with the subgraph of each of
+/// the channels listed in the releases' metadata (under the given key).
+fn serialize(
+    graphs: &HashMap<String, Graph>,
+    channel_key: &str,
+    signer: Option<&Signer>,
+) -> Result<Documents, Error> {
+    let mut documents = HashMap::new();
+    for (arch, graph) in graphs {
+        documents.insert((arch.clone(), None), Document::serialize(graph, signer)?);
+
+        for channel in channels(graph, channel_key) {
+            let subgraph = graph.filter(|release| in_channel(release, channel_key, &channel));
+            documents.insert(
+                (arch.clone(), Some(channel)),
+                Document::serialize(&subgraph, signer)?,
+            );
+        }
+    }
+    Ok(documents)
+}
+
+/// Lists the channels to which any of the releases in the graph belong.
+fn channels(graph: &Graph, channel_key: &str) -> HashSet<String> {
+    graph
+        .releases()
+        .filter_map(|release| match release {
+            Release::Concrete(release) => release.metadata.get(channel_key),
+            Release::Abstract(_) => None,
+        })
+        .flat_map(|channels| channels.split(','))
+        .map(str::trim)
+        .filter(|channel| !channel.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn in_channel(release: &Release, channel_key: &str, channel: &str) -> bool {
+    match release {
+        Release::Concrete(release) => release
+            .metadata
+            .get(channel_key)
+            .map(|channels| channels.split(',').any(|c| c.trim() == channel))
+            .unwrap_or(false),
+        Release::Abstract(_) => false,
+    }
+}
+
+/// Atomically replaces the contents of the given file with the serialized graphs. The v2 schema is
+/// used, so that the conditional edges are preserved.
+fn save(path: &Path, graphs: &HashMap<String, Graph>) -> Result<(), Error> {
+    let graphs: HashMap<_, _> = graphs.iter().map(|(arch, graph)| (arch, graph.v2())).collect();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(&graphs)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Builds a graph for each architecture, from the releases of that architecture. Releases with no
+/// known architecture are included in the graph of the default architecture, which is always
+/// present. If a graph data repository is configured, its data is merged into each of the graphs.
+fn create_graphs(
+    opts: &config::Options,
+    cache: &Cache,
+    credentials: Option<&Arc<Provider>>,
+) -> Result<HashMap<String, Graph>, Error> {
+    let graph_data = match opts.graph_data_repo {
+        Some(ref url) => Some(
+            GraphData::fetch(url, &opts.graph_data_ref, &opts.graph_data_dir)
+                .context("failed to fetch graph data")?,
+        ),
+        None => None,
+    };
+
+    let mut releases = fetch_releases(opts, cache, credentials)?;
+    releases.retain(|release| match release.metadata.kind {
+        MetadataKind::Unknown(ref kind) => {
+            warn!("Skipping {}: unknown metadata kind {}", release.source, kind);
+            false
+        }
+        _ => true,
+    });
+    // Hidden releases remain in the graph only as abstract releases, if other releases refer to
+    // them (e.g. as a previous version).
+    releases.retain(|release| {
+        let hidden = release.metadata.is_hidden(&opts.hidden_key);
+        if hidden {
+            info!("Hiding {} ({})", release.metadata.version, release.source);
+        }
+        !hidden
+    });
+    for release in &mut releases {
+        apply_v1_metadata(release, &opts.channel_key);
+    }
+    if let Some(ref data) = graph_data {
+        data.apply(&mut releases, &opts.channel_key);
+    }
+
+    let mut releases_by_arch = HashMap::new();
+    releases_by_arch.insert(opts.default_arch.clone(), Vec::new());
+    for release in releases {
+        let arch = release
+            .arch
+            .clone()
+            .unwrap_or_else(|| opts.default_arch.clone());
+        releases_by_arch
+            .entry(arch)
+            .or_insert_with(Vec::new)
+            .push(release);
+    }
+
+    releases_by_arch
+        .into_iter()
+        .map(|(arch, releases)| {
+            let releases = resolve_duplicates(releases, opts)
+                .context(format!("failed to resolve duplicate releases for {}", arch))?;
+            let mut graph = create_graph(releases)
+                .context(format!("failed to create graph for {}", arch))?;
+            if let Some(ref data) = graph_data {
+                data.add_conditional_edges(&mut graph)
+                    .context(format!("failed to add conditional edges to graph for {}", arch))?;
+            }
+            Ok((arch, graph))
+        })
+        .collect()
+}
+
+/// Applies the fields introduced with V1 of the metadata document to the given release: the
+/// architecture overrides the one reported by the source, and the channels are added to the ones
+/// listed under the given metadata key.
+fn apply_v1_metadata(release: &mut registry::Release, channel_key: &str) {
+    if let Some(ref arch) = release.metadata.arch {
+        release.arch = Some(arch.clone());
+    }
+
+    if !release.metadata.channels.is_empty() {
+        let metadata = &mut release.metadata;
+        graph_data::add_channels(&mut metadata.metadata, channel_key, &metadata.channels);
+    }
+}
+
+fn fetch_releases(
+    opts: &config::Options,
+    cache: &Cache,
+    credentials: Option<&Arc<Provider>>,
+) -> Result<Vec<registry::Release>, Error> {
+    let releases = match opts.source {
+        config::Source::Registry => registry::fetch_releases(opts, cache, credentials),
+        config::Source::File => match opts.path {
+            Some(ref path) => fs_scanner::fetch_releases(path, &opts.registry, &opts.repository),
+            None => Err(format_err!("the file source requires a path")),
+        },
+        config::Source::S3 => match opts.bucket {
+            Some(ref bucket) => s3_region(opts).and_then(|region| {
+                s3_scanner::fetch_releases(
+                    region,
+                    bucket,
+                    &opts.prefix,
+                    &opts.registry,
+                    &opts.repository,
+                )
+            }),
+            None => Err(format_err!("the s3 source requires a bucket")),
+        },
+    };
+
+    let releases = releases.context("failed to fetch all release metadata")?;
+    RELEASES.set(releases.len() as i64);
+    Ok(releases)
+}
+
+/// Picks a single release for each version found in more than one image, according to the
+/// configured policy. Releases are otherwise kept in the order in which they were found.
+fn resolve_duplicates(
+    releases: Vec<registry::Release>,
+    opts: &config::Options,
+) -> Result<Vec<registry::Release>, Error> {
+    let mut resolved: Vec<registry::Release> = Vec::with_capacity(releases.len());
+    let mut indices: HashMap<Version, usize> = HashMap::new();
+    for release in releases {
+        let index = match indices.get(&release.metadata.version) {
+            Some(&index) => index,
+            None => {
+                indices.insert(release.metadata.version.clone(), resolved.len());
+                resolved.push(release);
+                continue;
+            }
+        };
+
+        let existing = &resolved[index];
+        let version = &release.metadata.version;
+        let replace = match opts.duplicate_versions {
+            config::DuplicatePolicy::Error => bail!(
+                "release {} was found in both {} and {}",
+                version,
+                existing.source,
+                release.source
+            ),
+            config::DuplicatePolicy::Newest => release.created > existing.created,
+            config::DuplicatePolicy::DigestMatch => {
+                let key = opts.verify_digest_key.as_ref().map(String::as_str);
+                digest_matches(&release, key) && !digest_matches(existing, key)
+            }
+        };
+
+        let (kept, dropped) = if replace {
+            (&release.source, &existing.source)
+        } else {
+            (&existing.source, &release.source)
+        };
+        warn!(
+            "Release {} was found in both {} and {}; ignoring {}",
+            version, kept, dropped, dropped
+        );
+        if replace {
+            resolved[index] = release;
+        }
+    }
+    Ok(resolved)
+}
+
+/// Checks whether the digest of the given release's manifest is the one recorded in its metadata
+/// (under the given key) or the one its pull spec refers to. Releases whose source doesn't report
+/// the digest of their manifest never match.
+fn digest_matches(release: &registry::Release, key: Option<&str>) -> bool {
+    let actual = match release.digest {
+        Some(ref digest) => digest,
+        None => return false,
+    };
+    let recorded = key.and_then(|key| release.metadata.metadata.get(key));
+    let pinned = release.source.find('@').map(|at| &release.source[at + 1..]);
+    recorded == Some(actual) || pinned == Some(actual.as_str())
+}
+
+fn create_graph(releases: Vec<registry::Release>) -> Result<Graph, Error> {
     let mut graph = Graph::default();
+    let mut conditional_edges = Vec::new();
 
-    registry::fetch_releases(&opts.registry, &opts.repository)
-        .context("failed to fetch all release metadata")?
+    releases
         .into_iter()
         .try_for_each(|release| {
             let previous = release.metadata.previous.clone();
             let next = release.metadata.next.clone();
+            conditional_edges.extend(
+                release
+                    .metadata
+                    .conditional_edges
+                    .iter()
+                    .map(|edge| (release.metadata.version.clone(), edge.clone())),
+            );
             let current = graph.add_release(release)?;
 
             previous.iter().try_for_each(|version| {
@@ -96,5 +1139,311 @@ fn create_graph(opts: &config::Options) -> Result<Graph, Error> {
             })
         })?;
 
+    // Conditional edges to releases which aren't part of the graph are ignored.
+    for (from, edge) in conditional_edges {
+        let ids = (graph.find_by_version(&from), graph.find_by_version(&edge.to));
+        if let (Some(from), Some(to)) = ids {
+            graph.add_conditional_transition(&from, &to, edge.risks)?;
+        }
+    }
+
     Ok(graph)
 }
+
+fn s3_region(opts: &config::Options) -> Result<Region, Error> {
+    match opts.s3_endpoint {
+        Some(ref endpoint) => Ok(Region::Custom {
+            name: opts.s3_region.clone(),
+            endpoint: endpoint.clone(),
+        }),
+        None => Ok(opts.s3_region.parse()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use release::Metadata;
+    use signing::tests::RSA_KEY;
+    use std::io::Read;
+    use structopt::StructOpt;
+
+    fn options(args: &[&str]) -> config::Options {
+        config::Options::from_iter(["graph-builder"].iter().chain(args))
+    }
+
+    fn headers(entries: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn match_etags() {
+        let etag = "\"0123abcd-gzip\"";
+        let matches = |value| etag_matches(&headers(&[(header::IF_NONE_MATCH, value)]), etag);
+        assert!(matches("\"0123abcd-gzip\""));
+        assert!(matches("W/\"0123abcd-gzip\""));
+        assert!(matches("\"4567cdef\", \"0123abcd-gzip\""));
+        assert!(matches("*"));
+        assert!(!matches("\"0123abcd\""));
+        assert!(!matches("\"0123abcd-cbor\""));
+        assert!(!etag_matches(&HeaderMap::new(), etag));
+    }
+
+    #[test]
+    fn accept_gzip() {
+        let accepts = |value| accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, value)]));
+        assert!(accepts("gzip"));
+        assert!(accepts("deflate, gzip;q=0.5"));
+        assert!(accepts("gzip; q=1.0"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("deflate, br"));
+        assert!(!accepts("x-gzip"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn compress_documents() {
+        let json = r#"{"nodes":[{"version":"1.0.0"}],"edges":[]}"#;
+        let document = Document::new(json.to_string(), String::new(), Vec::new());
+        let mut decompressed = String::new();
+        GzDecoder::new(&document.gzip[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, json);
+    }
+
+    #[test]
+    fn negotiate_encodings() {
+        fn negotiate(entries: &[(header::HeaderName, &'static str)]) -> Option<Encoding> {
+            Encoding::negotiate(&headers(entries))
+        }
+        let (json, cbor) = (CONTENT_TYPE_GRAPH_V1, CONTENT_TYPE_CBOR);
+        assert_eq!(negotiate(&[(header::ACCEPT, json)]), Some(Encoding::Json));
+        assert_eq!(
+            negotiate(&[(header::ACCEPT, json), (header::ACCEPT_ENCODING, "gzip")]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(&[(header::ACCEPT, cbor)]), Some(Encoding::Cbor));
+        assert_eq!(
+            negotiate(&[(header::ACCEPT, cbor), (header::ACCEPT_ENCODING, "gzip")]),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(negotiate(&[(header::ACCEPT, "application/json")]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+
+    #[test]
+    fn encode_cbor_documents() {
+        let graph: Graph = serde_json::from_value(json!({
+            "nodes": [{"version": "1.0.0"}, {"version": "1.1.0"}],
+            "edges": [[0, 1]],
+        })).unwrap();
+        let document = Document::serialize(&graph, None).unwrap();
+        let decoded: Graph = serde_cbor::from_slice(&document.cbor).unwrap();
+        assert_eq!(serde_json::to_vec(&decoded).unwrap(), &document.json[..]);
+    }
+
+    #[test]
+    fn sign_documents() {
+        let graph: Graph = serde_json::from_value(json!({
+            "nodes": [{"version": "1.0.0"}, {"version": "1.1.0"}],
+            "edges": [[0, 1]],
+        })).unwrap();
+        assert!(Document::serialize(&graph, None).unwrap().signatures.is_none());
+
+        // RS256 signatures are deterministic, so each one must be the signature of exactly the
+        // bytes served in that encoding.
+        let signer = Signer::from_pem(RSA_KEY.as_bytes()).unwrap();
+        let document = Document::serialize(&graph, Some(&signer)).unwrap();
+        let signatures = document.signatures.unwrap();
+        assert_eq!(signatures.json, signer.sign(&document.json).unwrap());
+        assert_eq!(signatures.gzip, signer.sign(&document.gzip).unwrap());
+        assert_eq!(signatures.cbor, signer.sign(&document.cbor).unwrap());
+        assert_eq!(signatures.v2, signer.sign(&document.v2).unwrap());
+        assert_ne!(signatures.json, signatures.gzip);
+    }
+
+    #[test]
+    fn filter_channels() {
+        let graph: Graph = serde_json::from_value(json!({
+            "nodes": [
+                {"version": "1.0.0", "payload": "image/1.0.0", "metadata": {"channels": "a, b"}},
+                {"version": "1.1.0", "payload": "image/1.1.0", "metadata": {"channels": "b"}},
+                {"version": "1.2.0", "payload": "image/1.2.0", "metadata": {"channels": "a"}},
+                {"version": "1.3.0", "payload": "image/1.3.0", "metadata": {}},
+            ],
+            "edges": [[0, 1], [0, 2], [1, 2], [2, 3]],
+        })).unwrap();
+        let mut graphs = HashMap::new();
+        graphs.insert("amd64".to_string(), graph);
+        let documents = serialize(&graphs, "channels", None).unwrap();
+
+        let mut keys: Vec<_> = documents.keys().cloned().collect();
+        keys.sort();
+        let arch = |channel: Option<&str>| ("amd64".to_string(), channel.map(String::from));
+        assert_eq!(keys, [arch(None), arch(Some("a")), arch(Some("b"))]);
+
+        let versions = |channel: &str| -> Vec<String> {
+            let document = &documents[&arch(Some(channel))];
+            let graph: Graph = serde_json::from_slice(&document.json).unwrap();
+            let mut versions: Vec<_> =
+                graph.releases().map(|release| release.version().to_string()).collect();
+            versions.sort();
+            versions
+        };
+        assert_eq!(versions("a"), ["1.0.0", "1.2.0"]);
+        assert_eq!(versions("b"), ["1.0.0", "1.1.0"]);
+    }
+
+    fn release(source: &str, version: &str, created: &str, digest: &str) -> registry::Release {
+        let mut metadata = HashMap::new();
+        metadata.insert("digest".to_string(), "sha256:1111".to_string());
+        registry::Release {
+            source: source.to_string(),
+            arch: None,
+            created: DateTime::parse_from_rfc3339(created).ok(),
+            digest: Some(digest.to_string()),
+            metadata: Metadata {
+                kind: MetadataKind::V0,
+                version: Version::parse(version).unwrap(),
+                previous: Vec::new(),
+                next: Vec::new(),
+                metadata,
+                arch: None,
+                channels: Vec::new(),
+                conditional_edges: Vec::new(),
+            },
+        }
+    }
+
+    /// Returns the sources of the releases which are kept by the given duplicate version policy.
+    fn resolve(policy: &str, releases: Vec<registry::Release>) -> Result<Vec<String>, Error> {
+        let opts = options(&["--duplicate-versions", policy, "--verify-digest-key=digest"]);
+        let resolved = resolve_duplicates(releases, &opts)?;
+        Ok(resolved.into_iter().map(|release| release.source).collect())
+    }
+
+    #[test]
+    fn resolve_duplicate_versions() {
+        let releases = || {
+            vec![
+                release("repo:1.0.0", "1.0.0", "2018-01-01T00:00:00Z", "sha256:0000"),
+                release("repo:1.1.0", "1.1.0", "2018-02-01T00:00:00Z", "sha256:0000"),
+                release("repo:1.0.0-rebuild", "1.0.0", "2018-03-01T00:00:00Z", "sha256:1111"),
+                release("repo:1.0.0-old", "1.0.0", "2017-12-01T00:00:00Z", "sha256:2222"),
+            ]
+        };
+
+        assert!(resolve("error", releases()).is_err());
+        assert_eq!(
+            resolve("prefer-newest-tag", releases()).unwrap(),
+            ["repo:1.0.0-rebuild", "repo:1.1.0"]
+        );
+        assert_eq!(
+            resolve("prefer-digest-match", releases()).unwrap(),
+            ["repo:1.0.0-rebuild", "repo:1.1.0"]
+        );
+    }
+
+    #[test]
+    fn prefer_pinned_digests() {
+        let releases = vec![
+            release("repo:1.0.0", "1.0.0", "2018-02-01T00:00:00Z", "sha256:0000"),
+            release("repo@sha256:2222", "1.0.0", "2018-01-01T00:00:00Z", "sha256:2222"),
+        ];
+        assert_eq!(
+            resolve("prefer-digest-match", releases).unwrap(),
+            ["repo@sha256:2222"]
+        );
+    }
+
+    #[test]
+    fn keep_distinct_versions() {
+        let releases = vec![
+            release("repo:1.1.0", "1.1.0", "2018-02-01T00:00:00Z", "sha256:0000"),
+            release("repo:1.0.0", "1.0.0", "2018-01-01T00:00:00Z", "sha256:1111"),
+        ];
+        assert_eq!(resolve("error", releases).unwrap(), ["repo:1.1.0", "repo:1.0.0"]);
+    }
+
+    #[test]
+    fn back_off_after_failures() {
+        let opts = options(&["--period=30", "--max-backoff=100"]);
+        let delays: Vec<_> = [0, 1, 2, 40]
+            .iter()
+            .map(|failures| scan_delay(&opts, *failures).as_secs())
+            .collect();
+        assert_eq!(delays, [30, 60, 100, 100]);
+
+        let opts = options(&["--period=30"]);
+        assert_eq!(scan_delay(&opts, 5), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn add_jitter() {
+        let opts = options(&["--period=30", "--period-jitter=2"]);
+        for _ in 0..100 {
+            let delay = scan_delay(&opts, 0);
+            assert!(delay >= Duration::from_secs(30), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(32), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn detect_stale_graphs() {
+        let ago = |secs| SystemTime::now() - Duration::from_secs(secs);
+        let served = |modified| Served {
+            modified,
+            ..Served::default()
+        };
+        let state = State::new("amd64".into(), "channel".into(), Cache::new());
+        assert!(!state.is_stale(&served(Some(ago(3600)))));
+
+        let state = state.stale_after(Some(Duration::from_secs(600)));
+        assert!(!state.is_stale(&served(None)));
+        assert!(!state.is_stale(&served(Some(ago(60)))));
+        assert!(state.is_stale(&served(Some(ago(3600)))));
+
+        // A successful scan confirms the served graphs, even if it didn't change them.
+        let confirmed = |time: SystemTime| {
+            let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            state.confirmed.store(secs as usize, Ordering::SeqCst);
+        };
+        confirmed(ago(60));
+        assert!(!state.is_stale(&served(Some(ago(3600)))));
+        confirmed(ago(1200));
+        assert!(state.is_stale(&served(Some(ago(3600)))));
+    }
+
+    #[test]
+    fn reject_empty_scan() {
+        assert!(check_release_loss(10, 0, 100).is_err());
+        assert!(check_release_loss(0, 0, 10).is_ok());
+    }
+
+    #[test]
+    fn reject_consecutive_empty_scans() {
+        // Rejected scans leave the served graphs in place, so each one is compared against them.
+        let served = 10;
+        for _ in 0..2 {
+            assert!(check_release_loss(served, 0, 50).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_loss_over_threshold() {
+        assert!(check_release_loss(100, 89, 10).is_err());
+    }
+
+    #[test]
+    fn accept_loss_under_threshold() {
+        assert!(check_release_loss(100, 90, 10).is_ok());
+        assert!(check_release_loss(100, 91, 10).is_ok());
+        assert!(check_release_loss(100, 120, 10).is_ok());
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Curated graph data, kept in a git repository with the following layout:
+//!
+//! - `channels/<name>.json`: `{"versions": ["1.0.0", ...]}`, the releases in the channel
+//! - `blocked-edges/<any>.json`: `{"from": "1.0.0", "to": "1.1.0"}`, an update to remove. If the
+//!   document also lists `risks`, the update is instead served as a conditional edge (in the v2
+//!   graph only), which clients take only when none of the risks apply to them
+//! - `releases/<version>.json`: `{"previous": [...], "next": [...], "metadata": {...}}`, extra
+//!   update edges and metadata for the release with the given version
+//!
+//! All sections are optional.
+
+use cincinnati::{Graph, Risk};
+use failure::{Error, ResultExt};
+use registry::Release;
+use semver::Version;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+
+/// Metadata key under which the channels of a release are listed, separated by commas.
+pub const CHANNELS_KEY: &str = "io.openshift.upgrades.graph.release.channels";
+
+#[derive(Debug, Default)]
+pub struct GraphData {
+    channels: HashMap<Version, Vec<String>>,
+    blocked_edges: HashSet<(Version, Version)>,
+    conditional_edges: HashMap<(Version, Version), Vec<Risk>>,
+    releases: HashMap<Version, ReleaseData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Channel {
+    versions: Vec<Version>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlockedEdge {
+    from: Version,
+    to: Version,
+    #[serde(default)]
+    risks: Vec<Risk>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReleaseData {
+    #[serde(default)]
+    previous: Vec<Version>,
+    #[serde(default)]
+    next: Vec<Version>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+impl GraphData {
+    /// Clones (or updates an existing clone of) the given repository into the given directory,
+    /// checks out the given reference, and loads the graph data from it.
+    pub fn fetch(url: &str, reference: &str, dir: &Path) -> Result<GraphData, Error> {
+        if dir.join(".git").exists() {
+            git(dir, &["fetch", "--quiet", url, reference])?;
+            git(dir, &["reset", "--quiet", "--hard", "FETCH_HEAD"])?;
+        } else {
+            let dir = dir
+                .to_str()
+                .ok_or_else(|| format_err!("invalid graph data directory"))?;
+            git(
+                Path::new("."),
+                &["clone", "--quiet", "--branch", reference, url, dir],
+            )?;
+        }
+        GraphData::load(dir)
+    }
+
+    /// Loads the graph data from the given directory.
+    pub fn load(dir: &Path) -> Result<GraphData, Error> {
+        let mut data = GraphData::default();
+
+        for (name, channel) in read_documents::<Channel>(&dir.join("channels"))? {
+            for version in channel.versions {
+                data.channels
+                    .entry(version)
+                    .or_insert_with(Vec::new)
+                    .push(name.clone());
+            }
+        }
+
+        for (_, edge) in read_documents::<BlockedEdge>(&dir.join("blocked-edges"))? {
+            if !edge.risks.is_empty() {
+                data.conditional_edges
+                    .insert((edge.from.clone(), edge.to.clone()), edge.risks);
+            }
+            data.blocked_edges.insert((edge.from, edge.to));
+        }
+
+        for (name, release) in read_documents::<ReleaseData>(&dir.join("releases"))? {
+            let version = Version::parse(&name)
+                .context(format!("invalid release version in graph data: {}", name))?;
+            data.releases.insert(version, release);
+        }
+
+        Ok(data)
+    }
+
+    /// Merges the graph data into the given releases. Channel membership (listed under the given
+    /// metadata key, along with any channels the release already lists) and extra metadata are
+    /// added to the matching releases, as are extra update edges, and blocked edges are removed.
+    pub fn apply(&self, releases: &mut [Release], channel_key: &str) {
+        for release in releases.iter_mut() {
+            let metadata = &mut release.metadata;
+
+            if let Some(extra) = self.releases.get(&metadata.version) {
+                metadata.previous.extend(extra.previous.iter().cloned());
+                metadata.next.extend(extra.next.iter().cloned());
+                metadata.metadata.extend(
+                    extra
+                        .metadata
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.clone())),
+                );
+            }
+
+            if let Some(channels) = self.channels.get(&metadata.version) {
+                add_channels(&mut metadata.metadata, channel_key, channels);
+            }
+
+            let version = metadata.version.clone();
+            let blocked = &self.blocked_edges;
+            metadata
+                .previous
+                .retain(|previous| !blocked.contains(&(previous.clone(), version.clone())));
+            metadata
+                .next
+                .retain(|next| !blocked.contains(&(version.clone(), next.clone())));
+        }
+    }
+
+    /// Adds the blocked edges which have associated risks to the given graph as conditional
+    /// edges. Edges between releases which aren't part of the graph are ignored.
+    pub fn add_conditional_edges(&self, graph: &mut Graph) -> Result<(), Error> {
+        let mut edges: Vec<_> = self.conditional_edges.iter().collect();
+        edges.sort_by(|a, b| a.0.cmp(b.0));
+        for ((from, to), risks) in edges {
+            if let (Some(from), Some(to)) = (graph.find_by_version(from), graph.find_by_version(to))
+            {
+                graph.add_conditional_transition(&from, &to, risks.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds the given channels to the ones listed (separated by commas) under the given key of the
+/// given metadata.
+pub fn add_channels(
+    metadata: &mut HashMap<String, String>,
+    channel_key: &str,
+    channels: &[String],
+) {
+    let mut merged: Vec<String> = metadata
+        .get(channel_key)
+        .map(|listed| listed.split(',').map(|channel| channel.trim().to_string()).collect())
+        .unwrap_or_default();
+    merged.extend(channels.iter().cloned());
+    merged.retain(|channel| !channel.is_empty());
+    merged.sort();
+    merged.dedup();
+    metadata.insert(channel_key.to_string(), merged.join(","));
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<(), Error> {
+    trace!("running git {}", args.join(" "));
+
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    ensure!(
+        output.status.success(),
+        "git {} failed: {}",
+        args[0],
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Reads all of the JSON documents in the given directory, along with their names. A missing
+/// directory is treated as empty.
+fn read_documents<T>(dir: &Path) -> Result<Vec<(String, T)>, Error>
+where
+    T: DeserializeOwned,
+{
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = fs::read_dir(dir)
+        .context(format!("failed to read directory {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let mut documents = Vec::new();
+    for path in paths {
+        let name = match (path.extension(), path.file_stem()) {
+            (Some(ext), Some(stem)) if ext == OsStr::new("json") => {
+                stem.to_string_lossy().into_owned()
+            }
+            _ => continue,
+        };
+
+        let file = File::open(&path).context(format!("failed to open {}", path.display()))?;
+        documents.push((
+            name,
+            serde_json::from_reader(file).context(format!("failed to parse {}", path.display()))?,
+        ));
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cincinnati::{AbstractRelease, MatchingRule};
+    use serde_json::Value;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Writes the given graph data (keyed by path) into a new directory for the given test.
+    fn fixture(name: &str, documents: &[(&str, Value)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("graph-builder-data-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, document) in documents {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, document.to_string()).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn release(version: &str, previous: &[&str], next: &[&str], channels: &str) -> Release {
+        Release {
+            source: format!("quay.io/test/release:{}", version),
+            arch: None,
+            created: None,
+            digest: None,
+            metadata: serde_json::from_value(json!({
+                "kind": "cincinnati-metadata-v0",
+                "version": version,
+                "previous": previous,
+                "next": next,
+                "metadata": {CHANNELS_KEY: channels},
+            })).unwrap(),
+        }
+    }
+
+    fn versions(versions: &[Version]) -> Vec<String> {
+        versions.iter().map(Version::to_string).collect()
+    }
+
+    #[test]
+    fn merge_graph_data() {
+        let dir = fixture(
+            "merge",
+            &[
+                ("channels/fast.json", json!({"versions": ["1.0.0", "1.1.0", "1.2.0"]})),
+                ("channels/stable.json", json!({"versions": ["1.0.0", "1.1.0"]})),
+                ("blocked-edges/1.0.0-1.2.0.json", json!({"from": "1.0.0", "to": "1.2.0"})),
+                ("releases/1.1.0.json", json!({"next": ["1.2.0"], "metadata": {"extra": "yes"}})),
+                ("releases/README.md", json!("not a release")),
+            ],
+        );
+        let data = GraphData::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut releases = vec![
+            release("1.0.0", &[], &["1.1.0", "1.2.0"], ""),
+            release("1.1.0", &["1.0.0"], &[], "candidate"),
+            release("1.2.0", &["1.0.0"], &[], "fast"),
+        ];
+        data.apply(&mut releases, CHANNELS_KEY);
+
+        let metadata = |index: usize| &releases[index].metadata;
+        assert_eq!(versions(&metadata(0).next), ["1.1.0"]);
+        assert!(metadata(2).previous.is_empty());
+        assert_eq!(versions(&metadata(1).next), ["1.2.0"]);
+        assert_eq!(metadata(1).metadata["extra"], "yes");
+
+        let channels = |index: usize| metadata(index).metadata[CHANNELS_KEY].as_str();
+        assert_eq!(channels(0), "fast,stable");
+        assert_eq!(channels(1), "candidate,fast,stable");
+        assert_eq!(channels(2), "fast");
+    }
+
+    #[test]
+    fn add_conditional_edges() {
+        let risk = json!({
+            "url": "https://example.com/risk",
+            "name": "Risk",
+            "message": "Breaks things",
+            "matchingRules": [{"type": "Always"}],
+        });
+        let dir = fixture(
+            "conditional",
+            &[
+                (
+                    "blocked-edges/1.0.0-1.1.0.json",
+                    json!({"from": "1.0.0", "to": "1.1.0", "risks": [risk]}),
+                ),
+                (
+                    "blocked-edges/1.0.0-2.0.0.json",
+                    json!({"from": "1.0.0", "to": "2.0.0", "risks": [risk]}),
+                ),
+            ],
+        );
+        let data = GraphData::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Conditional edges are still removed from the v1 graph.
+        let mut releases = vec![release("1.0.0", &[], &["1.1.0"], "")];
+        data.apply(&mut releases, CHANNELS_KEY);
+        assert!(releases[0].metadata.next.is_empty());
+
+        let mut graph = Graph::default();
+        for version in &["1.0.0", "1.1.0"] {
+            graph
+                .add_release(cincinnati::Release::Abstract(AbstractRelease {
+                    version: Version::parse(version).unwrap(),
+                }))
+                .unwrap();
+        }
+        data.add_conditional_edges(&mut graph).unwrap();
+
+        let edges = graph.conditional_edges();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].edges.len(), 1);
+        assert_eq!(edges[0].edges[0].to, Version::parse("1.1.0").unwrap());
+        assert_eq!(edges[0].risks[0].matching_rules, [MatchingRule::Always]);
+    }
+
+    #[test]
+    fn load_empty_graph_data() {
+        let dir = fixture("empty", &[]);
+        let data = GraphData::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(data.channels.is_empty());
+        assert!(data.blocked_edges.is_empty());
+        assert!(data.releases.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_graph_data() {
+        let dir = fixture("invalid-version", &[("releases/latest.json", json!({}))]);
+        assert!(GraphData::load(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dir = fixture("invalid-channel", &[("channels/stable.json", json!({"versions": 1}))]);
+        assert!(GraphData::load(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_channels() {
+        let mut metadata = HashMap::new();
+        metadata.insert(CHANNELS_KEY.to_string(), "stable, fast,".to_string());
+        add_channels(
+            &mut metadata,
+            CHANNELS_KEY,
+            &["candidate".to_string(), "fast".to_string()],
+        );
+        assert_eq!(metadata[CHANNELS_KEY], "candidate,fast,stable");
+    }
+}
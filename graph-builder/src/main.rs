@@ -13,37 +13,73 @@
 // limitations under the License.
 
 extern crate actix_web;
+extern crate arc_swap;
+extern crate bytes;
+extern crate chrono;
 extern crate cincinnati;
 extern crate env_logger;
 extern crate itertools;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate failure;
 extern crate flate2;
+extern crate futures;
 #[macro_use]
 extern crate log;
+extern crate lru_cache;
+extern crate openssl;
+#[macro_use]
+extern crate prometheus;
+extern crate rand;
+extern crate redis;
 extern crate reqwest;
+extern crate rusoto_core;
+extern crate rusoto_ecr;
+extern crate rusoto_s3;
+extern crate rusqlite;
 extern crate semver;
 extern crate serde;
+extern crate serde_cbor;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate signal_hook;
 #[macro_use]
 extern crate structopt;
 extern crate tar;
+extern crate toml;
+extern crate url;
+extern crate zstd;
 
+mod admin;
+mod auth;
+mod cache;
 mod config;
+mod credentials;
+mod fs_scanner;
 mod graph;
+mod graph_data;
+mod metrics;
+mod oci;
+mod otlp;
+mod rate_limit;
 mod registry;
 mod release;
+mod s3_scanner;
+mod signing;
 
-use actix_web::{http::Method, middleware::Logger, server, App};
-use failure::Error;
+use actix_web::{http::Method, middleware::Logger, server, App, HttpRequest, Responder};
+use failure::{Error, ResultExt};
 use log::LevelFilter;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread;
-use structopt::StructOpt;
 
 fn main() -> Result<(), Error> {
-    let opts = config::Options::from_args();
+    let opts = config::Options::load()?;
 
     env_logger::Builder::from_default_env()
         .filter(
@@ -57,19 +93,147 @@ fn main() -> Result<(), Error> {
         )
         .init();
 
-    let state = graph::State::new();
+    let cache = match (opts.cache_path.as_ref(), opts.cache_redis.as_ref()) {
+        (Some(path), _) => cache::Cache::with_backend(Box::new(cache::Sqlite::open(path)?))?,
+        (None, Some(url)) => {
+            let redis = cache::Redis::open(url, opts.cache_redis_timeout)?;
+            cache::Cache::with_backend(Box::new(redis))?
+        }
+        (None, None) => cache::Cache::new(),
+    }.negative_ttl(opts.cache_negative_ttl)
+        .max_entries(opts.cache_max_entries);
+
+    if let Some(ref endpoint) = opts.otlp_endpoint {
+        otlp::start(endpoint.clone(), opts.trace_service_name.clone())?;
+    }
+
+    let credentials = credentials::provider(&opts)?;
+    if opts.oneshot {
+        return graph::render(&opts, &cache, credentials.as_ref());
+    }
+
+    let signer = match opts.signing_key {
+        Some(ref path) => Some(signing::Signer::from_file(path)?),
+        None => None,
+    };
+    let state = graph::State::new(opts.default_arch.clone(), opts.channel_key.clone(), cache)
+        .signer(signer)
+        .stale_after(opts.stale_after);
+    if let Some(ref path) = opts.state_file {
+        if path.exists() {
+            if let Err(err) = state.restore(path) {
+                warn!("Failed to restore graph from {}: {}", path.display(), err);
+            }
+        }
+    }
     let addr = (opts.address, opts.port);
+    let auth = match opts.require_auth_token {
+        Some(ref path) => Some(auth::BearerAuth::from_file(path)?),
+        None => None,
+    };
+    let limiter = opts.rate_limit
+        .map(|rate| rate_limit::RateLimiter::new(rate, opts.rate_limit_burst));
+    let tls = match (opts.cert.clone(), opts.key.clone()) {
+        (Some(cert), Some(key)) => {
+            let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .context("failed to load TLS key")?;
+            builder
+                .set_certificate_chain_file(cert)
+                .context("failed to load TLS certificate")?;
+            Some(builder)
+        }
+        _ => None,
+    };
 
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
     {
         let state = state.clone();
-        thread::spawn(move || graph::run(&opts, &state));
+        match opts.serve_from.clone() {
+            Some(path) => thread::spawn(move || graph::serve_from(&path, opts.period, &state)),
+            None => thread::spawn(move || graph::run(opts, credentials, &state, &reload)),
+        };
     }
 
-    server::new(move || {
-        App::with_state(state.clone())
+    let server = server::new(move || {
+        let app = App::with_state(state.clone())
             .middleware(Logger::default())
-            .route("/graph", Method::GET, graph::index)
-    }).bind(addr)?
-        .run();
+            .middleware(metrics::RequestMetrics)
+            .middleware(otlp::RequestTracing);
+        let graph = (auth.clone(), limiter.clone());
+        let protected = (auth.clone(), None);
+        let app = resource(app, "/graph", &graph, graph::index);
+        let app = resource(app, "/v2/graph", &graph, graph::index_v2);
+        let app = resource(app, "/graph/updates", &graph, graph::updates);
+        let app = resource(app, "/graph/stats", &protected, graph::stats);
+        let app = resource(app, "/status", &protected, graph::status);
+        let app = resource(app, "/status/scan", &protected, graph::scan_status);
+        let app = admin_resource(app, "/admin/cache", Method::GET, &auth, admin::list_cache);
+        let app = admin_resource(
+            app,
+            "/admin/cache/{key}",
+            Method::DELETE,
+            &auth,
+            admin::evict_cache,
+        );
+        app.route("/metrics", Method::GET, metrics::serve)
+    });
+    match tls {
+        Some(builder) => server.bind_ssl(addr, builder)?.run(),
+        None => server.bind(addr)?.run(),
+    }
     Ok(())
 }
+
+/// Middleware guarding a resource: the bearer token which clients must present and the rate limit
+/// applied to them, if any.
+type Guards = (Option<auth::BearerAuth>, Option<rate_limit::RateLimiter>);
+
+/// Adds a resource serving GET requests with the given handler, behind the given guards.
+fn resource<F, R>(
+    app: App<graph::State>,
+    path: &str,
+    guards: &Guards,
+    handler: F,
+) -> App<graph::State>
+where
+    F: Fn(HttpRequest<graph::State>) -> R + 'static,
+    R: Responder + 'static,
+{
+    let (auth, limiter) = guards.clone();
+    app.resource(path, move |r| {
+        if let Some(auth) = auth {
+            r.middleware(auth);
+        }
+        if let Some(limiter) = limiter {
+            r.middleware(limiter);
+        }
+        r.method(Method::GET).f(handler)
+    })
+}
+
+/// Adds an administrative resource serving requests of the given method with the given handler.
+/// Clients must present the bearer token if one is required, or else connect over the loopback
+/// interface, so that the cache can't be tampered with remotely.
+fn admin_resource<F, R>(
+    app: App<graph::State>,
+    path: &str,
+    method: Method,
+    auth: &Option<auth::BearerAuth>,
+    handler: F,
+) -> App<graph::State>
+where
+    F: Fn(HttpRequest<graph::State>) -> R + 'static,
+    R: Responder + 'static,
+{
+    let auth = auth.clone();
+    app.resource(path, move |r| {
+        match auth {
+            Some(auth) => r.middleware(auth),
+            None => r.middleware(auth::LoopbackOnly),
+        }
+        r.method(method).f(handler)
+    })
+}
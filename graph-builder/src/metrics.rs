@@ -0,0 +1,63 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::middleware::{Finished, Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use prometheus::{self, Encoder, HistogramVec, TextEncoder};
+use std::time::Instant;
+
+lazy_static! {
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "graph_builder_http_request_duration_seconds",
+        "Time taken to handle HTTP requests",
+        &["method", "status"]
+    ).unwrap();
+}
+
+/// Serves all registered metrics in the Prometheus text format.
+pub fn serve<S>(_req: HttpRequest<S>) -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => {
+            error!("Failed to encode metrics: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Middleware recording the duration of each HTTP request.
+pub struct RequestMetrics;
+
+struct RequestStart(Instant);
+
+impl<S> Middleware<S> for RequestMetrics {
+    fn start(&self, req: &mut HttpRequest<S>) -> Result<Started> {
+        req.extensions_mut().insert(RequestStart(Instant::now()));
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &mut HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if let Some(start) = req.extensions().get::<RequestStart>() {
+            let elapsed = start.0.elapsed();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[req.method().as_str(), resp.status().as_str()])
+                .observe(elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9);
+        }
+        Finished::Done
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, FixedOffset};
+use config;
+use failure::{Error, ResultExt};
+use registry::{self, Release};
+use release;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    created: Option<String>,
+    architecture: Option<String>,
+    config: Option<registry::ContainerConfig>,
+}
+
+/// Fetches a vector of all release metadata from the tagged images in the OCI image layout at the
+/// given path. Since a layout can't be pulled from, the payloads refer to the images within the
+/// given repository instead, which is expected to be the mirror serving the layout's contents.
+pub fn fetch_releases(layout: &Path, opts: &config::Options) -> Result<Vec<Release>, Error> {
+    let index: Index = read_json(&layout.join("index.json"))?;
+    let filter = registry::TagFilter::new(opts)?;
+
+    let mut manifests = Vec::new();
+    for descriptor in index.manifests {
+        let tag = match descriptor.annotations.get(ANNOTATION_REF_NAME) {
+            Some(tag) if descriptor.media_type == MEDIA_TYPE_MANIFEST => tag.clone(),
+            _ => {
+                debug!("skipping untagged or unsupported {}", descriptor.digest);
+                continue;
+            }
+        };
+        if !filter.allows(&tag) {
+            continue;
+        }
+        let manifest: Manifest = read_json(&blob_path(layout, &descriptor.digest)?)?;
+        let config: Option<Config> = blob_path(layout, &manifest.config.digest)
+            .and_then(|path| read_json(&path))
+            .ok();
+        manifests.push((tag, descriptor.digest, manifest, config.unwrap_or_default()));
+    }
+
+    if let Some(max_tags) = opts.max_tags {
+        manifests.sort_by(|(_, _, _, a), (_, _, _, b)| b.created().cmp(&a.created()));
+        manifests.truncate(max_tags);
+    }
+
+    let mut releases = Vec::new();
+    for (tag, digest, manifest, config) in manifests {
+        let source = format!("{}:{}", opts.repository, tag);
+        let metadata = match opts.metadata_source {
+            config::MetadataSource::Layers => read_metadata(layout, &manifest, opts),
+            config::MetadataSource::Labels => labels_metadata(&manifest, &config),
+        }.context(format!("failed to read metadata from {}", tag))?;
+        if let Some(ref key) = opts.verify_digest_key {
+            if !registry::digest_matches(&metadata, key, &source, Some(&digest)) {
+                continue;
+            }
+        }
+
+        releases.push(Release {
+            source,
+            arch: config.architecture.clone(),
+            created: config.created(),
+            digest: Some(digest),
+            metadata,
+        });
+    }
+    Ok(releases)
+}
+
+impl Config {
+    /// Returns the creation time of the image.
+    fn created(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.created.as_ref()?).ok()
+    }
+}
+
+/// Searches the layers of the given image for the metadata document. As when searching an image in
+/// a registry, failing to search any of the layers is an error unless another layer contains the
+/// document, so that an unreadable layer isn't mistaken for an image without metadata.
+fn read_metadata(
+    layout: &Path,
+    manifest: &Manifest,
+    opts: &config::Options,
+) -> Result<release::Metadata, Error> {
+    let mut failure = None;
+    for layer in &manifest.layers {
+        match opts.max_layer_size {
+            Some(max_size) if layer.size > max_size => {
+                debug!("skipping {}: layer is too large ({} bytes)", layer.digest, layer.size);
+                continue;
+            }
+            _ => trace!("reading metadata from {}", layer.digest),
+        }
+
+        let file = File::open(blob_path(layout, &layer.digest)?)
+            .context(format!("failed to open blob {}", layer.digest))?;
+        match registry::decompress(file)
+            .and_then(|layer| registry::read_metadata(layer, &opts.metadata_paths))
+        {
+            Ok(Some(metadata)) => return Ok(metadata),
+            Ok(None) => {}
+            Err(err) => {
+                debug!("failed to search layer {}: {}", layer.digest, err);
+                failure = failure.or(Some(err));
+            }
+        }
+    }
+
+    match failure {
+        Some(err) => Err(err.context("failed to search image for metadata").into()),
+        None => bail!("metadata document not found in image"),
+    }
+}
+
+/// Reads the release metadata from the labels of the image's configuration and the annotations of
+/// its manifest, which take precedence.
+fn labels_metadata(manifest: &Manifest, config: &Config) -> Result<release::Metadata, Error> {
+    let mut labels = config
+        .config
+        .as_ref()
+        .and_then(|config| config.labels.clone())
+        .unwrap_or_default();
+    labels.extend(manifest.annotations.clone());
+    release::Metadata::from_labels(&labels)?
+        .ok_or_else(|| format_err!("version label not found in image"))
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf, Error> {
+    let mut parts = digest.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(hex)) if !algorithm.contains('/') && !hex.contains('/') => {
+            Ok(layout.join("blobs").join(algorithm).join(hex))
+        }
+        _ => bail!("invalid digest: {}", digest),
+    }
+}
+
+fn read_json<T>(path: &Path) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let file = File::open(path).context(format!("failed to open {}", path.display()))?;
+    Ok(serde_json::from_reader(file).context(format!("failed to parse {}", path.display()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use release::VERSION_LABEL;
+    use serde_json::Value;
+    use std::{env, fs, process};
+    use structopt::StructOpt;
+
+    const DIGEST_KEY: &str = "io.openshift.upgrades.graph.release.manifest-digest";
+
+    /// Writes an image to the given layout, whose manifest annotations give the release's version
+    /// and the manifest digest recorded for it. Blobs aren't checked against their digests, so the
+    /// given digest is used as is.
+    fn write_image(layout: &Path, digest: &str, version: &str, recorded: &str) -> Value {
+        let manifest = json!({
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:missing",
+                "size": 0,
+            },
+            "layers": [],
+            "annotations": {VERSION_LABEL: version, DIGEST_KEY: recorded},
+        });
+        fs::write(blob_path(layout, digest).unwrap(), manifest.to_string()).unwrap();
+        json!({
+            "mediaType": MEDIA_TYPE_MANIFEST,
+            "digest": digest,
+            "size": 0,
+            "annotations": {ANNOTATION_REF_NAME: version},
+        })
+    }
+
+    #[test]
+    fn verify_digests() {
+        let layout = env::temp_dir().join(format!("graph-builder-oci-{}", process::id()));
+        let _ = fs::remove_dir_all(&layout);
+        fs::create_dir_all(layout.join("blobs").join("sha256")).unwrap();
+        let index = json!({
+            "manifests": [
+                write_image(&layout, "sha256:1111", "1.0.0", "sha256:1111"),
+                write_image(&layout, "sha256:2222", "2.0.0", "sha256:0000"),
+            ],
+        });
+        fs::write(layout.join("index.json"), index.to_string()).unwrap();
+
+        let opts = config::Options::from_iter(&[
+            "graph-builder",
+            "--metadata-source=labels",
+            &format!("--verify-digest-key={}", DIGEST_KEY),
+        ]);
+        let releases = fetch_releases(&layout, &opts).unwrap();
+        fs::remove_dir_all(&layout).unwrap();
+
+        let sources: Vec<_> = releases.iter().map(|release| &release.source).collect();
+        assert_eq!(sources, ["openshift:1.0.0"]);
+    }
+}
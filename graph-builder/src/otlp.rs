@@ -0,0 +1,378 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing of scans and HTTP requests. Finished spans are buffered in memory and exported
+//! periodically to an OpenTelemetry collector, using the JSON encoding of OTLP over HTTP. Nothing
+//! is recorded unless an exporter has been started.
+
+use actix_web::middleware::{Finished, Middleware, Started};
+use actix_web::{self, HttpRequest, HttpResponse};
+use failure::Error;
+use prometheus::IntCounter;
+use rand::{self, Rng};
+use reqwest;
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+lazy_static! {
+    static ref BUFFER: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+    static ref SPANS_DROPPED: IntCounter = register_int_counter!(
+        "graph_builder_trace_spans_dropped_total",
+        "Number of trace spans dropped without being exported"
+    ).unwrap();
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Context of the innermost span entered on this thread, if any.
+    static CURRENT: RefCell<Option<Context>> = RefCell::new(None);
+}
+
+/// Interval at which buffered spans are exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum number of spans buffered between exports. Further spans are dropped, so that an
+/// unavailable collector can't exhaust the memory of the server.
+const MAX_BUFFERED: usize = 4096;
+
+/// OTLP span kinds.
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+/// OTLP status code of a failed span.
+const STATUS_ERROR: u8 = 2;
+
+/// Starts exporting spans to the given OTLP/HTTP traces endpoint (e.g.
+/// `http://localhost:4318/v1/traces`) from a background thread.
+pub fn start(endpoint: Url, service: String) -> Result<(), Error> {
+    let http = reqwest::Client::builder().timeout(EXPORT_INTERVAL).build()?;
+    ENABLED.store(true, Ordering::SeqCst);
+    thread::spawn(move || loop {
+        thread::sleep(EXPORT_INTERVAL);
+        let spans = mem::replace(
+            &mut *BUFFER.lock().expect("trace buffer lock has been poisoned"),
+            Vec::new(),
+        );
+        if spans.is_empty() {
+            continue;
+        }
+        if let Err(err) = export(&http, &endpoint, &service, &spans) {
+            warn!("Failed to export {} trace spans: {}", spans.len(), err);
+            SPANS_DROPPED.inc_by(spans.len() as i64);
+        }
+    });
+    Ok(())
+}
+
+fn export(
+    http: &reqwest::Client,
+    endpoint: &Url,
+    service: &str,
+    spans: &[SpanData],
+) -> Result<(), Error> {
+    let request = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "graph-builder"},
+                "spans": spans,
+            }],
+        }],
+    });
+    let response = http.post(endpoint.clone()).json(&request).send()?;
+    ensure!(
+        response.status().is_success(),
+        "collector responded with {}",
+        response.status()
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+}
+
+#[derive(Serialize)]
+struct Attribute {
+    key: &'static str,
+    value: AttributeValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AttributeValue {
+    string_value: String,
+}
+
+#[derive(Serialize)]
+struct Status {
+    code: u8,
+    message: String,
+}
+
+/// Identifies a span, so that spans started on other threads can be recorded as its children.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Context {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+/// Returns the context of the current span of this thread, if any. Work handed to other threads
+/// is traced by passing this context along and entering spans within it.
+pub fn current() -> Option<Context> {
+    CURRENT.with(|current| *current.borrow())
+}
+
+/// A span of work, which is recorded when it's dropped.
+pub struct Span {
+    inner: Option<Inner>,
+}
+
+struct Inner {
+    context: Context,
+    parent_id: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<Attribute>,
+    status: Option<Status>,
+    /// Span which was current on this thread before this one was entered, if this one was.
+    previous: Option<Option<Context>>,
+}
+
+impl Span {
+    /// Starts a span as a child of the current span of this thread (or as the root of a new trace,
+    /// if there is none) and makes it the current span until it's dropped. Spans entered on a
+    /// thread must be dropped in the reverse order.
+    pub fn enter<N: Into<String>>(name: N) -> Span {
+        Span::enter_within(current(), name)
+    }
+
+    /// Starts a span as a child of the given span (which may have been started on another thread)
+    /// and makes it the current span of this thread until it's dropped.
+    pub fn enter_within<N: Into<String>>(parent: Option<Context>, name: N) -> Span {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Span { inner: None };
+        }
+        let mut inner = Inner::new(name.into(), KIND_INTERNAL, parent);
+        inner.previous = Some(current());
+        CURRENT.with(|current| *current.borrow_mut() = Some(inner.context));
+        Span { inner: Some(inner) }
+    }
+
+    /// Starts a span serving a request, as the root of a new trace. Requests are handled
+    /// concurrently on the same thread, so the span doesn't become the current span.
+    pub fn request<N: Into<String>>(name: N) -> Span {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Span { inner: None };
+        }
+        Span {
+            inner: Some(Inner::new(name.into(), KIND_SERVER, None)),
+        }
+    }
+
+    /// Adds an attribute to the span.
+    pub fn attribute<V: Display>(&mut self, key: &'static str, value: V) {
+        if let Some(ref mut inner) = self.inner {
+            inner.attributes.push(Attribute {
+                key,
+                value: AttributeValue {
+                    string_value: value.to_string(),
+                },
+            });
+        }
+    }
+
+    /// Marks the span as failed with the given error.
+    pub fn fail<E: Display>(&mut self, err: E) {
+        if let Some(ref mut inner) = self.inner {
+            inner.status = Some(Status {
+                code: STATUS_ERROR,
+                message: err.to_string(),
+            });
+        }
+    }
+}
+
+impl Inner {
+    fn new(name: String, kind: u8, parent: Option<Context>) -> Inner {
+        let mut rng = rand::thread_rng();
+        Inner {
+            context: Context {
+                trace_id: parent.map(|parent| parent.trace_id).unwrap_or_else(|| rng.gen()),
+                span_id: rng.gen(),
+            },
+            parent_id: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            status: None,
+            previous: None,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let inner = match self.inner.take() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if let Some(previous) = inner.previous {
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+        let span = SpanData {
+            trace_id: hex(&inner.context.trace_id),
+            span_id: hex(&inner.context.span_id),
+            parent_span_id: inner.parent_id.as_ref().map(|id| hex(id)),
+            name: inner.name,
+            kind: inner.kind,
+            start_time_unix_nano: unix_nanos(inner.start),
+            end_time_unix_nano: unix_nanos(SystemTime::now()),
+            attributes: inner.attributes,
+            status: inner.status,
+        };
+        let mut buffer = BUFFER.lock().expect("trace buffer lock has been poisoned");
+        if buffer.len() < MAX_BUFFERED {
+            buffer.push(span);
+        } else {
+            SPANS_DROPPED.inc();
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Formats the given time as nanoseconds since the Unix epoch. OTLP encodes 64-bit integers as
+/// strings in JSON.
+fn unix_nanos(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())).to_string()
+}
+
+/// Middleware recording a span for each HTTP request.
+pub struct RequestTracing;
+
+struct RequestSpan(Span);
+
+impl<S> Middleware<S> for RequestTracing {
+    fn start(&self, req: &mut HttpRequest<S>) -> actix_web::Result<Started> {
+        let mut span = Span::request(format!("{} {}", req.method(), req.path()));
+        span.attribute("http.method", req.method());
+        span.attribute("http.target", req.path());
+        req.extensions_mut().insert(RequestSpan(span));
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &mut HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if let Some(RequestSpan(mut span)) = req.extensions_mut().remove::<RequestSpan>() {
+            span.attribute("http.status_code", resp.status().as_u16());
+            if resp.status().is_server_error() {
+                span.fail(resp.status());
+            }
+        }
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// Removes the recorded spans with the given names from the buffer, leaving those of other
+    /// tests in place.
+    fn take_spans(names: &[&str]) -> Vec<Value> {
+        let mut buffer = BUFFER.lock().unwrap();
+        let (taken, kept) = buffer
+            .drain(..)
+            .partition(|span| names.contains(&span.name.as_str()));
+        *buffer = kept;
+        taken
+            .into_iter()
+            .map(|span: SpanData| serde_json::to_value(span).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nest_spans() {
+        ENABLED.store(true, Ordering::SeqCst);
+        let outer = Span::enter("outer");
+        let mut inner = Span::enter("inner");
+        inner.attribute("tag", "4.0.0");
+        inner.fail("not found");
+        drop(inner);
+        let request = Span::request("GET /graph");
+        drop(request);
+        drop(outer);
+        assert!(current().is_none());
+
+        let spans = take_spans(&["outer", "inner", "GET /graph"]);
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap();
+        let (outer, inner, request) = (span("outer"), span("inner"), span("GET /graph"));
+        assert_eq!(inner["traceId"], outer["traceId"]);
+        assert_eq!(inner["parentSpanId"], outer["spanId"]);
+        assert_eq!(inner["attributes"][0]["value"]["stringValue"], "4.0.0");
+        assert_eq!(inner["status"]["code"], STATUS_ERROR);
+        assert!(outer.get("parentSpanId").is_none());
+        assert_ne!(request["traceId"], outer["traceId"]);
+        assert_eq!(request["kind"], KIND_SERVER);
+        assert_eq!(outer["traceId"].as_str().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn enter_spans_within_other_threads() {
+        ENABLED.store(true, Ordering::SeqCst);
+        let scan = Span::enter("scan");
+        let parent = current();
+        thread::spawn(move || {
+            let _worker = Span::enter_within(parent, "worker");
+            let _nested = Span::enter("nested");
+        }).join()
+            .unwrap();
+        drop(scan);
+
+        let spans = take_spans(&["scan", "worker", "nested"]);
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap();
+        let (scan, worker, nested) = (span("scan"), span("worker"), span("nested"));
+        assert_eq!(worker["traceId"], scan["traceId"]);
+        assert_eq!(worker["parentSpanId"], scan["spanId"]);
+        assert_eq!(nested["traceId"], scan["traceId"]);
+        assert_eq!(nested["parentSpanId"], worker["spanId"]);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Middleware limiting the rate of requests from each client address, using a token bucket per
+/// client. Requests exceeding the limit are rejected with 429 (Too Many Requests).
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+/// Token buckets of the clients, along with the time at which full buckets were last dropped.
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    swept: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Returns the number of tokens in the bucket at the given time.
+    fn tokens(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+impl RateLimiter {
+    /// Minimum interval between sweeps of the full buckets, so that the cost of a sweep is spread
+    /// over many requests even when there are many clients.
+    const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Creates a limiter allowing the given number of requests per second from each client, with
+    /// bursts of up to `burst` requests.
+    pub fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            buckets: Arc::new(Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    fn allow(&self, addr: IpAddr) -> bool {
+        self.allow_at(addr, Instant::now())
+    }
+
+    /// Takes a token from the bucket of the given client at the given time, if there is one left.
+    fn allow_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let (rate, burst) = (self.rate, self.burst);
+        let mut buckets = self.buckets
+            .lock()
+            .expect("rate limit lock has been poisoned");
+
+        // Buckets which have refilled completely are equivalent to new ones, so drop them rather
+        // than keeping an entry for every client ever seen.
+        if buckets.clients.len() > 1024
+            && now.duration_since(buckets.swept) >= RateLimiter::SWEEP_INTERVAL
+        {
+            buckets
+                .clients
+                .retain(|_, bucket| bucket.tokens(now, rate, burst) < burst);
+            buckets.swept = now;
+        }
+
+        let bucket = buckets.clients.entry(addr).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens(now, rate, burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl<S> Middleware<S> for RateLimiter {
+    fn start(&self, req: &mut HttpRequest<S>) -> Result<Started> {
+        match req.peer_addr() {
+            Some(addr) if !self.allow(addr.ip()) => {
+                debug!("Rate limiting request from {}", addr.ip());
+                Ok(Started::Response(HttpResponse::TooManyRequests().finish()))
+            }
+            _ => Ok(Started::Done),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn refill_buckets() {
+        let limiter = RateLimiter::new(2, 3);
+        let client = addr("192.0.2.1");
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(client, start));
+        }
+        assert!(!limiter.allow_at(client, start));
+
+        // Tokens are added at the given rate, one every 500ms.
+        assert!(!limiter.allow_at(client, at(400)));
+        assert!(limiter.allow_at(client, at(500)));
+        assert!(!limiter.allow_at(client, at(500)));
+
+        // Buckets don't hold more than the burst, however long the client has been idle.
+        let later = 60_000;
+        for _ in 0..3 {
+            assert!(limiter.allow_at(client, at(later)));
+        }
+        assert!(!limiter.allow_at(client, at(later)));
+    }
+
+    #[test]
+    fn limit_clients_separately() {
+        let limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+        assert!(limiter.allow_at(addr("192.0.2.1"), now));
+        assert!(!limiter.allow_at(addr("192.0.2.1"), now));
+        assert!(limiter.allow_at(addr("2001:db8::1"), now));
+    }
+
+    #[test]
+    fn allow_bursts_of_at_least_one() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+        assert!(limiter.allow_at(addr("192.0.2.1"), now));
+        assert!(!limiter.allow_at(addr("192.0.2.1"), now));
+    }
+
+    #[test]
+    fn sweep_full_buckets() {
+        let limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        for i in 0..1025u32 {
+            assert!(limiter.allow_at(IpAddr::from(Ipv4Addr::from(0x0a00_0000 + i)), start));
+        }
+        let clients = || limiter.buckets.lock().unwrap().clients.len();
+        assert_eq!(clients(), 1025);
+
+        // Buckets aren't swept more than once a minute, even once they have refilled.
+        let refilled = start + Duration::from_secs(10);
+        assert!(limiter.allow_at(addr("192.0.2.1"), refilled));
+        assert_eq!(clients(), 1026);
+
+        let swept = start + RateLimiter::SWEEP_INTERVAL;
+        assert!(limiter.allow_at(addr("192.0.2.2"), swept));
+        assert_eq!(clients(), 1);
+    }
+}
@@ -12,18 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cache::Cache;
+use chrono::{DateTime, FixedOffset};
 use cincinnati;
-use failure::{Error, ResultExt};
+use config;
+use credentials::{Credentials, Provider};
+use failure::{Error, Fail, ResultExt};
 use flate2::read::GzDecoder;
+use oci;
+use otlp;
+use prometheus::IntCounter;
 use release;
-use reqwest::{self, Url};
+use reqwest::header::{Authorization, Bearer, ContentLength};
+use reqwest::{self, Response, StatusCode, Url};
+use semver::Version;
 use serde_json;
-use std::io::Read;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{cmp, fmt, thread};
 use tar::Archive;
+use zstd;
+
+lazy_static! {
+    static ref PROGRESS: Progress = Progress::default();
+    static ref DIGEST_MISMATCHES: IntCounter = register_int_counter!(
+        "graph_builder_digest_mismatches_total",
+        "Number of releases dropped because their metadata didn't match the image's digest"
+    ).unwrap();
+}
 
 pub struct Release {
     pub source: String,
+    pub arch: Option<String>,
+    /// Creation time of the image, if the source records one
+    pub created: Option<DateTime<FixedOffset>>,
+    /// Digest of the image's manifest, if the source reports one
+    pub digest: Option<String>,
     pub metadata: release::Metadata,
 }
 
@@ -37,24 +66,554 @@ impl Into<cincinnati::Release> for Release {
     }
 }
 
-/// Fetches a vector of all release metadata from the given repository, hosted on the given
-/// registry.
-pub fn fetch_releases(registry: &str, repo: &str) -> Result<Vec<Release>, Error> {
-    let mut metadata = Vec::new();
-    for tag in fetch_tags(registry, repo)? {
-        metadata.push(Release {
-            source: format!(
-                "{}/{}:{}",
-                registry
-                    .trim_left_matches("https://")
-                    .trim_left_matches("http://"),
-                repo,
-                tag
-            ),
-            metadata: fetch_metadata(registry, repo, &tag)?,
+/// Fetches a vector of all release metadata from the configured repository, hosted on the
+/// configured registry. If a maximum number of tags is set, only the metadata of that many of the
+/// most recently created images is read. A registry of the form `oci:<path>` refers to an OCI
+/// image layout on disk.
+///
+/// If the registry is unavailable, the scan is retried against each of the mirrors in turn. The
+/// payloads of the releases always refer to the registry itself. If a scan timeout is set, the scan
+/// (including any retries) is abandoned once it has run for that long. Requests are authenticated
+/// with the credentials of the given provider, if any.
+pub fn fetch_releases(
+    opts: &config::Options,
+    cache: &Cache,
+    credentials: Option<&Arc<Provider>>,
+) -> Result<Vec<Release>, Error> {
+    if opts.registry.starts_with("oci:") {
+        let layout = Path::new(opts.registry.trim_left_matches("oci:"));
+        return oci::fetch_releases(layout, opts);
+    }
+
+    // Each request is bounded separately, so that a stalled request fails (and can be retried
+    // against a mirror) well before the deadline of the whole scan.
+    let request_timeout = match opts.scan_timeout {
+        Some(timeout) => cmp::min(timeout, Client::REQUEST_TIMEOUT),
+        None => Client::REQUEST_TIMEOUT,
+    };
+    let http = reqwest::Client::builder().timeout(request_timeout).build()?;
+    let deadline = opts.scan_timeout.map(|timeout| Instant::now() + timeout);
+    let client = |endpoint: &str| -> Result<Client, Error> {
+        Ok(Client {
+            base: Url::parse(endpoint)?,
+            http: http.clone(),
+            deadline,
+            credentials: credentials.cloned(),
+            token: Arc::new(Mutex::new(None)),
+            anonymous_fallback: opts.anonymous_fallback,
+            anonymous: Arc::new(AtomicBool::new(false)),
         })
+    };
+
+    let mut result =
+        client(&opts.registry).and_then(|client| fetch_releases_from(&client, opts, cache));
+    for mirror in &opts.mirrors {
+        match result {
+            Err(ref err) if is_unavailable(err) => {
+                warn!("{}; retrying against mirror {}", err, mirror);
+            }
+            _ => break,
+        }
+        result = client(mirror).and_then(|client| fetch_releases_from(&client, opts, cache));
+    }
+    result
+}
+
+fn fetch_releases_from(
+    client: &Client,
+    opts: &config::Options,
+    cache: &Cache,
+) -> Result<Vec<Release>, Error> {
+    let repo = &opts.repository;
+    PROGRESS.reset();
+    let filter = TagFilter::new(opts)?;
+    let tags = {
+        let mut span = otlp::Span::enter("list tags");
+        span.attribute("registry", &client.base);
+        fetch_tags(client, repo)?
+    };
+    let tags: Vec<_> = tags.into_iter().filter(|tag| filter.allows(tag)).collect();
+    PROGRESS.tags.store(tags.len(), Ordering::SeqCst);
+    let mut manifests = {
+        let _span = otlp::Span::enter("fetch manifests");
+        fetch_manifests(client, repo, tags, opts.manifest_concurrency)?
+    };
+    if let Some(max_tags) = opts.max_tags {
+        newest_images(&mut manifests, max_tags);
+    }
+
+    let mut releases = Vec::new();
+    for (tag, manifest) in manifests {
+        let mut span = otlp::Span::enter("read release metadata");
+        span.attribute("image", format!("{}:{}", repo, tag));
+        let metadata = match opts.metadata_source {
+            config::MetadataSource::Layers => cached_metadata(client, opts, cache, &tag, &manifest),
+            config::MetadataSource::Labels => release::Metadata::from_labels(&manifest.labels())
+                .context(format!("invalid metadata labels in {}:{}", repo, tag))
+                .map_err(Error::from),
+        };
+        if let Err(ref err) = metadata {
+            span.fail(err);
+        }
+        let metadata = metadata?;
+
+        PROGRESS.images.fetch_add(1, Ordering::SeqCst);
+        let metadata =
+            metadata.ok_or_else(|| format_err!("metadata document not found in {}:{}", repo, tag))?;
+        if let Some(ref key) = opts.verify_digest_key {
+            let image = format!("{}:{}", repo, tag);
+            if !digest_matches(&metadata, key, &image, manifest.digest.as_ref()) {
+                continue;
+            }
+        }
+
+        releases.push(Release {
+            source: payload(&opts.registry, repo, &tag),
+            arch: Some(manifest.architecture.clone()),
+            created: manifest.created(),
+            digest: manifest.digest.clone(),
+            metadata,
+        })
+    }
+    Ok(releases)
+}
+
+/// Keeps only the given number of the most recently created images. The registry API doesn't
+/// expose when a tag was pushed, so images are ordered by the creation time recorded in their
+/// manifests instead. Images whose manifests don't record one come last, ordered by their tags:
+/// tags which are versions first, newest version first, followed by any other tags in reverse
+/// lexical order.
+fn newest_images(manifests: &mut Vec<(String, Manifest)>, max_images: usize) {
+    let mut keyed: Vec<_> = manifests
+        .drain(..)
+        .map(|(tag, manifest)| (recency(&tag, manifest.created()), tag, manifest))
+        .collect();
+    keyed.sort_by(|a, b| b.0.cmp(&a.0));
+    manifests.extend(keyed.into_iter().take(max_images).map(|(_, tag, manifest)| (tag, manifest)));
+}
+
+/// Returns the key by which images are ordered from the oldest to the newest, given their tags
+/// and creation times.
+fn recency(
+    tag: &str,
+    created: Option<DateTime<FixedOffset>>,
+) -> (Option<DateTime<FixedOffset>>, Option<Version>, String) {
+    (created, Version::parse(tag.trim_left_matches('v')).ok(), tag.to_string())
+}
+
+/// Returns the release metadata found in the layers of the given image, which is cached by the
+/// digest of the image's manifest so that the layers are only searched once.
+fn cached_metadata(
+    client: &Client,
+    opts: &config::Options,
+    cache: &Cache,
+    tag: &str,
+    manifest: &Manifest,
+) -> Result<Option<release::Metadata>, Error> {
+    let repo = &opts.repository;
+    let key = manifest.cache_key();
+    let image = format!("{}:{}", repo, tag);
+    let cached = {
+        let mut span = otlp::Span::enter("cache lookup");
+        let cached = cache.get(&key);
+        cache.tag(&key, &image);
+        span.attribute("cache.hit", cached.is_some());
+        cached
+    };
+    match cached {
+        Some(metadata) => {
+            trace!("using cached metadata for {} ({})", image, key);
+            Ok(metadata)
+        }
+        None => {
+            debug!("metadata for {} ({}) is not cached", image, key);
+            let metadata = {
+                let mut span = otlp::Span::enter("search layers");
+                span.attribute("manifest", &key);
+                fetch_metadata(client, opts, manifest).map_err(|err| {
+                    span.fail(&err);
+                    err
+                })?
+            };
+            cache.insert(key.clone(), metadata.clone());
+            cache.tag(&key, &image);
+            Ok(metadata)
+        }
+    }
+}
+
+/// Checks that the digest recorded under the given key of the release metadata (if any) matches
+/// the digest of the image's manifest, counting the releases which don't. Releases can't be
+/// checked if the source didn't report the digest of the manifest.
+pub fn digest_matches(
+    metadata: &release::Metadata,
+    key: &str,
+    image: &str,
+    digest: Option<&String>,
+) -> bool {
+    match (metadata.metadata.get(key), digest) {
+        (Some(expected), Some(actual)) if expected != actual => {
+            warn!(
+                "Dropping {}: metadata refers to {}, but the image's manifest is {}",
+                image, expected, actual
+            );
+            DIGEST_MISMATCHES.inc();
+            false
+        }
+        (Some(_), None) => {
+            debug!("unable to verify {}: the source didn't report a digest", image);
+            true
+        }
+        _ => true,
     }
-    Ok(metadata)
+}
+
+/// Counts of the work done so far by the current (or last) scan of the registry.
+#[derive(Default)]
+struct Progress {
+    tags: AtomicUsize,
+    manifests: AtomicUsize,
+    images: AtomicUsize,
+    blobs: AtomicUsize,
+}
+
+impl Progress {
+    fn reset(&self) {
+        for counter in &[&self.tags, &self.manifests, &self.images, &self.blobs] {
+            counter.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Snapshot of the progress of a registry scan.
+#[derive(Serialize)]
+pub struct ScanProgress {
+    /// Number of tags listed in the repository
+    pub tags_discovered: usize,
+    /// Number of image manifests fetched
+    pub manifests_fetched: usize,
+    /// Number of images whose release metadata has been read (from the cache or the image)
+    pub images_processed: usize,
+    /// Number of layer blobs downloaded while searching for release metadata
+    pub blobs_downloaded: usize,
+}
+
+/// Returns the progress of the current scan of the registry or, if no scan is running, of the
+/// last one. If the scan was retried against a mirror, only the progress of the last attempt is
+/// reported.
+pub fn progress() -> ScanProgress {
+    ScanProgress {
+        tags_discovered: PROGRESS.tags.load(Ordering::SeqCst),
+        manifests_fetched: PROGRESS.manifests.load(Ordering::SeqCst),
+        images_processed: PROGRESS.images.load(Ordering::SeqCst),
+        blobs_downloaded: PROGRESS.blobs.load(Ordering::SeqCst),
+    }
+}
+
+/// Indicates that the registry couldn't be reached or failed to handle a request.
+#[derive(Debug)]
+struct Unavailable(String);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "registry is unavailable: {}", self.0)
+    }
+}
+
+impl Fail for Unavailable {}
+
+fn is_unavailable(err: &Error) -> bool {
+    err.causes()
+        .any(|cause| cause.downcast_ref::<Unavailable>().is_some())
+}
+
+/// Client for the API of a single registry, which gives up once the deadline of the scan passes.
+///
+/// Requests carry the credentials of the provider (if any) using basic authentication. Once the
+/// registry challenges the client to present a bearer token instead, a token is requested from the
+/// registry's token service and used for the remaining requests.
+///
+/// If anonymous fallback is enabled and the credentials are rejected, the client warns and makes
+/// the remaining requests anonymously, so that public repositories can still be scanned with
+/// expired credentials.
+#[derive(Clone)]
+struct Client {
+    base: Url,
+    http: reqwest::Client,
+    deadline: Option<Instant>,
+    credentials: Option<Arc<Provider>>,
+    token: Arc<Mutex<Option<String>>>,
+    anonymous_fallback: bool,
+    anonymous: Arc<AtomicBool>,
+}
+
+impl Client {
+    /// Maximum duration of a single request to the registry.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Performs a GET request for the given path, reporting connection failures and server errors
+    /// as `Unavailable`.
+    fn get(&self, path: &str) -> Result<Response, Error> {
+        if let Some(deadline) = self.deadline {
+            ensure!(Instant::now() < deadline, "scan deadline exceeded");
+        }
+
+        let url = self.base.join(path)?;
+        let mut response = self.send_authenticated(&url)?;
+        if self.fall_back(response.status()) {
+            response = self.send_authenticated(&url)?;
+        }
+        if response.status().is_server_error() {
+            return Err(Unavailable(response.status().to_string()).into());
+        }
+        Ok(response)
+    }
+
+    /// Sends a GET request for the given URL. If the request is refused with a bearer challenge, a
+    /// new token is requested and the request is retried once.
+    fn send_authenticated(&self, url: &Url) -> Result<Response, Error> {
+        let response = self.send(url)?;
+        if response.status() == StatusCode::Unauthorized {
+            if let Some(challenge) = bearer_challenge(&response) {
+                self.authenticate(&challenge)?;
+                return self.send(url);
+            }
+        }
+        Ok(response)
+    }
+
+    fn send(&self, url: &Url) -> Result<Response, Error> {
+        let mut request = self.http.get(url.clone());
+        let token = self.token.lock().expect("token lock has been poisoned").clone();
+        match token {
+            Some(token) => {
+                request.header(Authorization(Bearer { token }));
+            }
+            None => {
+                if let Some(credentials) = self.credentials()? {
+                    request.basic_auth(credentials.username, Some(credentials.password));
+                }
+            }
+        }
+        request
+            .send()
+            .map_err(|err| Unavailable(err.to_string()).into())
+    }
+
+    /// Returns the credentials for this registry, if the provider has any and the client hasn't
+    /// fallen back to anonymous requests.
+    fn credentials(&self) -> Result<Option<Credentials>, Error> {
+        if self.anonymous.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        match self.credentials {
+            Some(ref provider) => Ok(provider
+                .credentials(&registry_host(&self.base))
+                .context("failed to get registry credentials")?),
+            None => Ok(None),
+        }
+    }
+
+    /// Requests a bearer token from the token service named in the given challenge, presenting the
+    /// credentials for this registry (if any), and uses it for the following requests.
+    fn authenticate(&self, challenge: &Challenge) -> Result<(), Error> {
+        trace!("requesting token from {}", challenge.realm);
+
+        let realm = Url::parse(&challenge.realm)?;
+        let mut request = self.http.get(realm.clone());
+        let mut query = Vec::new();
+        if let Some(ref service) = challenge.service {
+            query.push(("service", service));
+        }
+        if let Some(ref scope) = challenge.scope {
+            query.push(("scope", scope));
+        }
+        request.query(&query);
+        // The registry's credentials are only sent to a token service on another host if the
+        // connection is encrypted.
+        let trusted = realm.scheme() == "https" || realm.host_str() == self.base.host_str();
+        if let (true, Some(credentials)) = (trusted, self.credentials()?) {
+            request.basic_auth(credentials.username, Some(credentials.password));
+        }
+
+        let mut response = request
+            .send()
+            .map_err(|err| Unavailable(err.to_string()))
+            .context("failed to request registry token")?;
+        if self.fall_back(response.status()) {
+            return self.authenticate(challenge);
+        }
+        if response.status().is_server_error() {
+            return Err(Unavailable(response.status().to_string()).into());
+        }
+        ensure!(
+            response.status().is_success(),
+            "failed to request registry token: {}",
+            response.status()
+        );
+
+        let token: Token = response.json().context("failed to parse registry token")?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| format_err!("token service didn't return a token"))?;
+        *self.token.lock().expect("token lock has been poisoned") = Some(token);
+        Ok(())
+    }
+
+    /// Falls back to anonymous requests if the given status of a request shows that the
+    /// credentials were rejected, and fallback is enabled. Returns whether the request should be
+    /// retried.
+    fn fall_back(&self, status: StatusCode) -> bool {
+        if !self.anonymous_fallback
+            || self.credentials.is_none()
+            || (status != StatusCode::Unauthorized && status != StatusCode::Forbidden)
+            || self.anonymous.swap(true, Ordering::SeqCst)
+        {
+            return false;
+        }
+
+        warn!(
+            "credentials for {} were rejected ({}); falling back to anonymous requests",
+            registry_host(&self.base),
+            status
+        );
+        *self.token.lock().expect("token lock has been poisoned") = None;
+        true
+    }
+}
+
+/// Formats the host (and port, if any) of the given registry URL, as credentials are keyed.
+fn registry_host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Parameters of a bearer challenge, naming the token service from which a token is requested.
+#[derive(Debug, Default)]
+struct Challenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Parses the bearer challenge of the given response, if it has one.
+fn bearer_challenge(response: &Response) -> Option<Challenge> {
+    let header = response.headers().get_raw("WWW-Authenticate")?.one()?;
+    parse_challenge(::std::str::from_utf8(header).ok()?)
+}
+
+/// Parses a challenge of the form `Bearer realm="...",service="...",scope="..."`. Values may be
+/// quoted, in which case they may contain commas (e.g. a scope with several actions).
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    const SCHEME: &str = "Bearer ";
+    if !header.get(..SCHEME.len())?.eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+
+    let mut challenge = Challenge::default();
+    let mut rest = &header[SCHEME.len()..];
+    loop {
+        rest = rest.trim_left_matches(|c| c == ',' || c == ' ');
+        if rest.is_empty() {
+            break;
+        }
+
+        let equals = rest.find('=')?;
+        let key = rest[..equals].trim().to_lowercase();
+        rest = &rest[equals + 1..];
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"')? + 1;
+            let value = &rest[1..end];
+            rest = &rest[end + 1..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim();
+            rest = &rest[end..];
+            value
+        };
+
+        match key.as_str() {
+            "realm" => challenge.realm = value.to_string(),
+            "service" => challenge.service = Some(value.to_string()),
+            "scope" => challenge.scope = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if challenge.realm.is_empty() {
+        None
+    } else {
+        Some(challenge)
+    }
+}
+
+/// Formats the pull spec of the given tag, within the given repository and registry.
+pub fn payload(registry: &str, repo: &str, tag: &str) -> String {
+    format!(
+        "{}/{}:{}",
+        registry
+            .trim_left_matches("https://")
+            .trim_left_matches("http://"),
+        repo,
+        tag
+    )
+}
+
+/// Explicit lists of the tags to scan and the tags to exclude from the scan. The lists given in
+/// files are read once per scan, so that they can be changed without a restart.
+pub struct TagFilter {
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+}
+
+impl TagFilter {
+    pub fn new(opts: &config::Options) -> Result<TagFilter, Error> {
+        let mut allow: HashSet<_> = opts.tag_allow.iter().cloned().collect();
+        if let Some(ref path) = opts.tag_allow_file {
+            allow.extend(read_tags(path)?);
+        }
+        let mut deny: HashSet<_> = opts.tag_deny.iter().cloned().collect();
+        if let Some(ref path) = opts.tag_deny_file {
+            deny.extend(read_tags(path)?);
+        }
+        Ok(TagFilter { allow, deny })
+    }
+
+    /// Returns whether the given tag is to be scanned: it must be allowed (if any tags are) and
+    /// not denied.
+    pub fn allows(&self, tag: &str) -> bool {
+        let allowed =
+            (self.allow.is_empty() || self.allow.contains(tag)) && !self.deny.contains(tag);
+        if !allowed {
+            debug!("skipping filtered tag {}", tag);
+        }
+        allowed
+    }
+}
+
+/// Reads a list of tags, one per line. Blank lines and lines starting with `#` are ignored.
+fn read_tags(path: &Path) -> Result<Vec<String>, Error> {
+    let file = File::open(path).context(format!("failed to open {}", path.display()))?;
+    let mut tags = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context(format!("failed to read {}", path.display()))?;
+        let tag = line.trim();
+        if !tag.is_empty() && !tag.starts_with('#') {
+            tags.push(tag.to_string());
+        }
+    }
+    Ok(tags)
 }
 
 #[derive(Debug, Deserialize)]
@@ -63,10 +622,10 @@ struct Tags {
     tags: Vec<String>,
 }
 
-fn fetch_tags(registry: &str, repo: &str) -> Result<Vec<String>, Error> {
-    let base = Url::parse(registry)?;
+fn fetch_tags(client: &Client, repo: &str) -> Result<Vec<String>, Error> {
     let tags: Tags = {
-        let mut response = reqwest::get(base.join(&format!("v2/{}/tags/list", repo))?)
+        let mut response = client
+            .get(&format!("v2/{}/tags/list", repo))
             .context("failed to fetch image tags")?;
         ensure!(
             response.status().is_success(),
@@ -89,48 +648,232 @@ struct Manifest {
     architecture: String,
     #[serde(rename = "fsLayers")]
     fs_layers: Vec<Layer>,
+    #[serde(default)]
+    history: Vec<History>,
+    /// Content digest of the manifest, as reported by the registry
+    #[serde(skip)]
+    digest: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Manifest {
+    /// Returns the key under which the metadata of the image is cached. This is the digest of the
+    /// manifest or, if the registry didn't report one, the list of layer digests.
+    fn cache_key(&self) -> String {
+        match self.digest {
+            Some(ref digest) => digest.clone(),
+            None => self.fs_layers
+                .iter()
+                .map(|layer| layer.blob_sum.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+
+    /// Returns the creation time of the image, as recorded in its most recent history entry.
+    fn created(&self) -> Option<DateTime<FixedOffset>> {
+        let history = self.history.first()?;
+        let config: V1Compatibility = serde_json::from_str(&history.v1_compatibility).ok()?;
+        DateTime::parse_from_rfc3339(&config.created?).ok()
+    }
+
+    /// Returns the labels of the image, as recorded in its most recent history entry.
+    fn labels(&self) -> HashMap<String, String> {
+        self.history
+            .first()
+            .and_then(|history| {
+                serde_json::from_str::<V1Compatibility>(&history.v1_compatibility).ok()
+            })
+            .and_then(|config| config.config?.labels)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 struct Layer {
     #[serde(rename = "blobSum")]
     blob_sum: String,
 }
 
-fn fetch_metadata(registry: &str, repo: &str, tag: &str) -> Result<release::Metadata, Error> {
-    trace!("fetching metadata from {}/{}:{}", registry, repo, tag);
+#[derive(Debug, Deserialize)]
+struct History {
+    #[serde(rename = "v1Compatibility")]
+    v1_compatibility: String,
+}
 
-    let base = Url::parse(registry)?;
-    let manifest: Manifest = {
-        let mut response = reqwest::get(base.join(&format!("v2/{}/manifests/{}", repo, tag))?)
-            .context("failed to fetch image manifest")?;
-        ensure!(
-            response.status().is_success(),
-            "failed to fetch image manifest: {}",
-            response.status()
-        );
+#[derive(Debug, Deserialize)]
+struct V1Compatibility {
+    created: Option<String>,
+    config: Option<ContainerConfig>,
+}
 
-        serde_json::from_str(&response.text()?).context("failed to parse image manifest")?
-    };
+#[derive(Debug, Deserialize)]
+pub struct ContainerConfig {
+    #[serde(rename = "Labels")]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+fn fetch_manifest(client: &Client, repo: &str, tag: &str) -> Result<Manifest, Error> {
+    trace!("fetching manifest for {}:{}", repo, tag);
+
+    let mut response = client
+        .get(&format!("v2/{}/manifests/{}", repo, tag))
+        .context("failed to fetch image manifest")?;
+    ensure!(
+        response.status().is_success(),
+        "failed to fetch image manifest: {}",
+        response.status()
+    );
+
+    let digest = response
+        .headers()
+        .get_raw("Docker-Content-Digest")
+        .and_then(|raw| raw.one())
+        .map(|digest| String::from_utf8_lossy(digest).into_owned());
+    let mut manifest: Manifest =
+        serde_json::from_str(&response.text()?).context("failed to parse image manifest")?;
+    manifest.digest = digest;
+    Ok(manifest)
+}
+
+/// Fetches the manifests of the given tags, up to the given number at a time. The manifests are
+/// returned in the order of the tags; failing to fetch any of them is an error.
+fn fetch_manifests(
+    client: &Client,
+    repo: &str,
+    tags: Vec<String>,
+    concurrency: usize,
+) -> Result<Vec<(String, Manifest)>, Error> {
+    let tags = Arc::new(tags);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    let span = otlp::current();
+
+    for _ in 0..cmp::min(cmp::max(concurrency, 1), tags.len()) {
+        let client = client.clone();
+        let repo = repo.to_string();
+        let tags = tags.clone();
+        let next = next.clone();
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let index = next.fetch_add(1, Ordering::SeqCst);
+            let tag = match tags.get(index) {
+                Some(tag) => tag,
+                None => break,
+            };
+            let mut span = otlp::Span::enter_within(span, "fetch manifest");
+            span.attribute("tag", tag);
+            let result = fetch_manifest(&client, &repo, tag);
+            if let Err(ref err) = result {
+                span.fail(err);
+            }
+            drop(span);
+            PROGRESS.manifests.fetch_add(1, Ordering::SeqCst);
+            // Once the receiver has given up on the scan, the remaining tags are abandoned.
+            if tx.send((index, result)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(tx);
+
+    let mut manifests: Vec<Option<Manifest>> = tags.iter().map(|_| None).collect();
+    for (index, result) in rx {
+        manifests[index] = Some(result?);
+    }
+    Ok(tags.iter()
+        .cloned()
+        .zip(manifests.into_iter().map(|manifest| manifest.expect("manifest was fetched")))
+        .collect())
+}
 
-    for layer in manifest.fs_layers {
-        match fetch_metadata_from_layer(&base, repo, &layer) {
-            Ok(metadata) => return Ok(metadata),
-            Err(err) => debug!("metadata document not found in layer: {}", err),
+/// Searches the layers of the given image for the metadata document, downloading up to the
+/// configured number of layers at a time. Once the document has been found, the remaining
+/// downloads are abandoned. If none of the layers contain the document, `None` is returned;
+/// failing to search any of the layers is an error.
+fn fetch_metadata(
+    client: &Client,
+    opts: &config::Options,
+    manifest: &Manifest,
+) -> Result<Option<release::Metadata>, Error> {
+    trace!("fetching metadata from {}:{}", opts.repository, manifest.tag);
+
+    let layers = Arc::new(manifest.fs_layers.clone());
+    let paths = Arc::new(opts.metadata_paths.clone());
+    let max_layer_size = opts.max_layer_size;
+    let next = Arc::new(AtomicUsize::new(0));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let span = otlp::current();
+
+    for _ in 0..cmp::min(cmp::max(opts.layer_concurrency, 1), layers.len()) {
+        let client = client.clone();
+        let repo = opts.repository.clone();
+        let layers = layers.clone();
+        let paths = paths.clone();
+        let next = next.clone();
+        let cancelled = cancelled.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            while !cancelled.load(Ordering::SeqCst) {
+                let layer = match layers.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    Some(layer) => layer,
+                    None => break,
+                };
+                let mut span = otlp::Span::enter_within(span, "fetch layer");
+                span.attribute("layer", &layer.blob_sum);
+                let result = fetch_metadata_from_layer(
+                    &client,
+                    &repo,
+                    layer,
+                    max_layer_size,
+                    &paths,
+                    &cancelled,
+                );
+                if let Err(ref err) = result {
+                    span.fail(err);
+                }
+                drop(span);
+                if tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut failure = None;
+    for result in rx {
+        match result {
+            Ok(Some(metadata)) => {
+                cancelled.store(true, Ordering::SeqCst);
+                return Ok(Some(metadata));
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("failed to search layer: {}", err);
+                failure = failure.or(Some(err));
+            }
         }
     }
 
-    bail!("metadata document not found in image")
+    match failure {
+        Some(err) => Err(err.context("failed to search image for metadata").into()),
+        None => Ok(None),
+    }
 }
 
 fn fetch_metadata_from_layer(
-    base: &Url,
+    client: &Client,
     repo: &str,
     layer: &Layer,
-) -> Result<release::Metadata, Error> {
+    max_size: Option<u64>,
+    paths: &[PathBuf],
+    cancelled: &AtomicBool,
+) -> Result<Option<release::Metadata>, Error> {
     trace!("fetching metadata from {}", layer.blob_sum);
 
-    let response = reqwest::get(base.join(&format!("v2/{}/blobs/{}", repo, layer.blob_sum))?)
+    let response = client
+        .get(&format!("v2/{}/blobs/{}", repo, layer.blob_sum))
         .context("failed to fetch image blob")?;
 
     ensure!(
@@ -138,29 +881,198 @@ fn fetch_metadata_from_layer(
         "failed to fetch metadata document: {}",
         response.status()
     );
+    PROGRESS.blobs.fetch_add(1, Ordering::SeqCst);
 
-    let mut archive = Archive::new(GzDecoder::new(response));
-    match archive
-        .entries()?
-        .filter_map(|entry| match entry {
-            Ok(file) => Some(file),
-            Err(err) => {
-                debug!("failed to read archive entry: {}", err);
-                None
-            }
-        })
-        .find(|file| match file.header().path() {
-            Ok(path) => path == Path::new("cincinnati.json"),
-            Err(err) => {
-                debug!("failed to read file header: {}", err);
-                false
-            }
-        }) {
-        Some(mut file) => {
+    // Schema 1 manifests don't record the size of each layer, so the length of the blob is checked
+    // before any of it is read instead.
+    if let (Some(max_size), Some(&ContentLength(size))) =
+        (max_size, response.headers().get::<ContentLength>())
+    {
+        if size > max_size {
+            debug!("skipping {}: layer is too large ({} bytes)", layer.blob_sum, size);
+            return Ok(None);
+        }
+    }
+
+    read_metadata(
+        decompress(Cancellable {
+            inner: response,
+            cancelled,
+        })?,
+        paths,
+    )
+}
+
+/// Reader which fails once the search it is part of has been cancelled.
+struct Cancellable<'a, R> {
+    inner: R,
+    cancelled: &'a AtomicBool,
+}
+
+impl<'a, R: Read> Read for Cancellable<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "search was cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Wraps the given layer blob in the decoder matching its leading bytes. Gzip and zstd compressed
+/// tarballs are supported, as are uncompressed ones.
+pub fn decompress<'a, R: Read + 'a>(layer: R) -> Result<Box<Read + 'a>, Error> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+    let mut layer = BufReader::new(layer);
+    let (gzip, zstd) = {
+        let magic = layer.fill_buf()?;
+        (magic.starts_with(GZIP_MAGIC), magic.starts_with(ZSTD_MAGIC))
+    };
+
+    Ok(if gzip {
+        Box::new(GzDecoder::new(layer))
+    } else if zstd {
+        Box::new(zstd::Decoder::new(layer)?)
+    } else {
+        Box::new(layer)
+    })
+}
+
+/// Searches the given (uncompressed) layer tarball for the metadata document, which may be at any
+/// of the given paths. Returns `None` if the document isn't present.
+pub fn read_metadata<R: Read>(
+    layer: R,
+    paths: &[PathBuf],
+) -> Result<Option<release::Metadata>, Error> {
+    let mut archive = Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut file = entry.context("failed to read archive entry")?;
+        let path = file.header()
+            .path()
+            .context("failed to read file header")?
+            .into_owned();
+        if paths.iter().any(|candidate| path == *candidate) {
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
-            serde_json::from_str(&contents).context("failed to parse cincinnati.json")
+            return Ok(Some(serde_json::from_str(&contents)
+                .context(format!("failed to parse {}", path.display()))?));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::{env, fs, process};
+    use tar::{Builder, Header};
+
+    #[test]
+    fn parse_quoted_challenge() {
+        let challenge = parse_challenge(
+            r#"Bearer realm="https://auth.example.com/token",service="registry.example.com",scope="repository:openshift/release:pull,push""#,
+        ).unwrap();
+        assert_eq!(challenge.realm, "https://auth.example.com/token");
+        assert_eq!(challenge.service.unwrap(), "registry.example.com");
+        assert_eq!(challenge.scope.unwrap(), "repository:openshift/release:pull,push");
+    }
+
+    #[test]
+    fn parse_unquoted_challenge() {
+        let challenge =
+            parse_challenge("bearer realm=https://auth.example.com/token, Service=registry")
+                .unwrap();
+        assert_eq!(challenge.realm, "https://auth.example.com/token");
+        assert_eq!(challenge.service.unwrap(), "registry");
+        assert!(challenge.scope.is_none());
+    }
+
+    #[test]
+    fn reject_invalid_challenges() {
+        assert!(parse_challenge(r#"Basic realm="registry""#).is_none());
+        assert!(parse_challenge(r#"Bearer service="registry""#).is_none());
+        assert!(parse_challenge(r#"Bearer realm="https://auth.example.com/token"#).is_none());
+        assert!(parse_challenge("Bearer").is_none());
+    }
+
+    #[test]
+    fn read_tag_list() {
+        let path = env::temp_dir().join(format!("graph-builder-tags-{}", process::id()));
+        fs::write(&path, "4.1.0\n\n# retracted\n  4.1.1  \n#4.1.2\n").unwrap();
+        let tags = read_tags(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(tags.unwrap(), vec!["4.1.0", "4.1.1"]);
+
+        assert!(read_tags(&path).is_err());
+    }
+
+    #[test]
+    fn filter_tags() {
+        let filter = TagFilter {
+            allow: HashSet::new(),
+            deny: vec!["4.1.1".to_string()].into_iter().collect(),
+        };
+        assert!(filter.allows("4.1.0"));
+        assert!(!filter.allows("4.1.1"));
+
+        let filter = TagFilter {
+            allow: vec!["4.1.0".to_string(), "4.1.1".to_string()].into_iter().collect(),
+            deny: vec!["4.1.1".to_string()].into_iter().collect(),
+        };
+        assert!(filter.allows("4.1.0"));
+        assert!(!filter.allows("4.1.1"));
+        assert!(!filter.allows("4.1.2"));
+    }
+
+    #[test]
+    fn order_images_by_recency() {
+        let created = |time: &str| Some(DateTime::parse_from_rfc3339(time).unwrap());
+        let mut keys = vec![
+            recency("4.1.0", created("2019-01-02T00:00:00Z")),
+            recency("4.2.0", None),
+            recency("4.0.1", created("2019-01-03T00:00:00Z")),
+            recency("latest", None),
+            recency("4.10.0", None),
+        ];
+        keys.sort_by(|a, b| b.cmp(a));
+        let tags: Vec<_> = keys.into_iter().map(|(_, _, tag)| tag).collect();
+        assert_eq!(tags, vec!["4.0.1", "4.1.0", "4.10.0", "4.2.0", "latest"]);
+    }
+
+    /// Returns an uncompressed layer tarball holding a metadata document at the given path.
+    fn layer(path: &str) -> Vec<u8> {
+        let document = br#"{"kind": "cincinnati-metadata-v0", "version": "1.0.0"}"#;
+        let mut header = Header::new_gnu();
+        header.set_size(document.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = Builder::new(Vec::new());
+        builder.append_data(&mut header, path, &document[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn search(layer: &[u8]) -> Option<release::Metadata> {
+        let paths = [PathBuf::from("release-manifests/release-metadata")];
+        read_metadata(decompress(layer).unwrap(), &paths).unwrap()
+    }
+
+    #[test]
+    fn decompress_layers() {
+        let plain = layer("release-manifests/release-metadata");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        let zstd = zstd::encode_all(&plain[..], 0).unwrap();
+
+        for layer in &[plain, gzip, zstd] {
+            assert_eq!(search(layer).unwrap().version, Version::new(1, 0, 0));
         }
-        None => bail!("cincinnati.json not found"),
-    }.map_err(Into::into)
+        assert!(search(&layer("etc/release-metadata")).is_none());
+    }
 }
@@ -12,22 +12,181 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cincinnati::Risk;
+use failure::{Error, ResultExt};
 use itertools::Itertools;
 use semver::Version;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::ser::{Serialize, Serializer};
+use serde_json::{self, Map, Value};
 use std::collections::HashMap;
 use std::fmt;
 
-#[derive(Debug, Deserialize)]
+/// Metadata key marking a release as hidden, unless it is set to "false". Hidden releases are left
+/// out of the graph, so that a release can be retracted without deleting its tag.
+pub const HIDDEN_KEY: &str = "io.openshift.upgrades.graph.release.hidden";
+
+/// Prefix of the image labels which are read as release metadata.
+pub const LABEL_PREFIX: &str = "io.openshift.upgrades.graph.";
+/// Image label holding the version of the release.
+pub const VERSION_LABEL: &str = "io.openshift.upgrades.graph.release.version";
+/// Image label listing the (comma-separated) versions which can update to the release.
+pub const PREVIOUS_LABEL: &str = "io.openshift.upgrades.graph.release.previous";
+/// Image label listing the (comma-separated) versions to which the release can update.
+pub const NEXT_LABEL: &str = "io.openshift.upgrades.graph.release.next";
+
+/// Metadata of a release, as read from a metadata document. The kind of the document is read
+/// first, and the rest of the document is then read according to that kind: the fields introduced
+/// with V1 are only read from V1 documents, and documents of unknown kinds aren't read any further
+/// (leaving the version unset, at 0.0.0), so that they can be reported and skipped.
+#[derive(Clone, Debug, Serialize)]
 pub struct Metadata {
-    kind: MetadataKind,
+    pub kind: MetadataKind,
     pub version: Version,
+    pub previous: Vec<Version>,
+    pub next: Vec<Version>,
+    pub metadata: HashMap<String, String>,
 
+    // The following fields were introduced with V1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+    #[serde(rename = "conditionalEdges", skip_serializing_if = "Vec::is_empty")]
+    pub conditional_edges: Vec<ConditionalEdge>,
+}
+
+/// Fields of a V0 metadata document.
+#[derive(Deserialize)]
+struct V0Document {
+    version: Version,
     #[serde(default)]
-    pub previous: Vec<Version>,
+    previous: Vec<Version>,
     #[serde(default)]
-    pub next: Vec<Version>,
+    next: Vec<Version>,
     #[serde(default)]
-    pub metadata: HashMap<String, String>,
+    metadata: HashMap<String, String>,
+}
+
+/// Fields of a V1 metadata document, which extends V0 with the architecture, the channels, and
+/// the conditional edges of the release.
+#[derive(Deserialize)]
+struct V1Document {
+    #[serde(flatten)]
+    v0: V0Document,
+    #[serde(default)]
+    arch: Option<String>,
+    #[serde(default)]
+    channels: Vec<String>,
+    #[serde(default, rename = "conditionalEdges")]
+    conditional_edges: Vec<ConditionalEdge>,
+}
+
+impl<'de> Deserialize<'de> for Metadata {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut document = Map::deserialize(deserializer)?;
+        let kind = match document.remove("kind") {
+            Some(kind) => MetadataKind::deserialize(kind).map_err(D::Error::custom)?,
+            None => return Err(D::Error::missing_field("kind")),
+        };
+        let document = Value::Object(document);
+
+        match kind {
+            MetadataKind::V0 => {
+                let v0 = serde_json::from_value(document).map_err(D::Error::custom)?;
+                Ok(Metadata::from_v0(kind, v0))
+            }
+            MetadataKind::V1 => {
+                let v1: V1Document = serde_json::from_value(document).map_err(D::Error::custom)?;
+                Ok(Metadata {
+                    arch: v1.arch,
+                    channels: v1.channels,
+                    conditional_edges: v1.conditional_edges,
+                    ..Metadata::from_v0(kind, v1.v0)
+                })
+            }
+            MetadataKind::Unknown(_) => Ok(Metadata::from_v0(
+                kind,
+                V0Document {
+                    version: Version::new(0, 0, 0),
+                    previous: Vec::new(),
+                    next: Vec::new(),
+                    metadata: HashMap::new(),
+                },
+            )),
+        }
+    }
+}
+
+impl Metadata {
+    /// Returns the metadata of the given kind with the fields of a V0 document, leaving the fields
+    /// introduced with V1 empty.
+    fn from_v0(kind: MetadataKind, v0: V0Document) -> Metadata {
+        Metadata {
+            kind,
+            version: v0.version,
+            previous: v0.previous,
+            next: v0.next,
+            metadata: v0.metadata,
+            arch: None,
+            channels: Vec::new(),
+            conditional_edges: Vec::new(),
+        }
+    }
+
+    /// Reads the metadata of a release from the labels of its image. The version, previous, and
+    /// next releases are read from their respective labels, and any other labels with the metadata
+    /// prefix are kept as the release's metadata. Returns `None` if the image has no version label.
+    pub fn from_labels(labels: &HashMap<String, String>) -> Result<Option<Metadata>, Error> {
+        let version = match labels.get(VERSION_LABEL) {
+            Some(version) => Version::parse(version.trim())
+                .context(format!("invalid version label: {}", version))?,
+            None => return Ok(None),
+        };
+        let versions = |label| -> Result<Vec<Version>, Error> {
+            match labels.get(label) {
+                Some(versions) => versions
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|version| !version.is_empty())
+                    .map(|version| {
+                        Version::parse(version)
+                            .context(format!("invalid version in {}: {}", label, version))
+                            .map_err(Error::from)
+                    })
+                    .collect(),
+                None => Ok(Vec::new()),
+            }
+        };
+
+        Ok(Some(Metadata {
+            kind: MetadataKind::V0,
+            version,
+            previous: versions(PREVIOUS_LABEL)?,
+            next: versions(NEXT_LABEL)?,
+            metadata: labels
+                .iter()
+                .filter(|(key, _)| {
+                    key.starts_with(LABEL_PREFIX)
+                        && ![VERSION_LABEL, PREVIOUS_LABEL, NEXT_LABEL].contains(&key.as_str())
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            arch: None,
+            channels: Vec::new(),
+            conditional_edges: Vec::new(),
+        }))
+    }
+
+    /// Returns whether the release is marked as hidden under the given metadata key.
+    pub fn is_hidden(&self, key: &str) -> bool {
+        self.metadata
+            .get(key)
+            .map_or(false, |value| !value.trim().eq_ignore_ascii_case("false"))
+    }
 }
 
 impl fmt::Display for Metadata {
@@ -43,8 +202,57 @@ impl fmt::Display for Metadata {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Update to a later release which is only recommended when none of the risks apply.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConditionalEdge {
+    pub to: Version,
+    pub risks: Vec<Risk>,
+}
+
+/// Schema of a metadata document. Documents of unknown kinds are still parsed (so that they can be
+/// reported and skipped), but only the fields of the known kinds are read from them.
+#[derive(Clone, Debug, PartialEq)]
 pub enum MetadataKind {
-    #[serde(rename = "cincinnati-metadata-v0")]
     V0,
+    V1,
+    Unknown(String),
+}
+
+impl MetadataKind {
+    fn as_str(&self) -> &str {
+        match self {
+            MetadataKind::V0 => "cincinnati-metadata-v0",
+            MetadataKind::V1 => "cincinnati-metadata-v1",
+            MetadataKind::Unknown(kind) => kind,
+        }
+    }
+}
+
+impl fmt::Display for MetadataKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for MetadataKind {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for MetadataKind {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let kind = String::deserialize(deserializer)?;
+        Ok(match kind.as_str() {
+            "cincinnati-metadata-v0" => MetadataKind::V0,
+            "cincinnati-metadata-v1" => MetadataKind::V1,
+            _ => MetadataKind::Unknown(kind),
+        })
+    }
 }
@@ -0,0 +1,100 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use failure::{Error, ResultExt};
+use futures::{Future, Stream};
+use registry::{self, Release};
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, S3, S3Client};
+use serde_json;
+
+/// Reads all of the release metadata documents stored under the given prefix of the given bucket.
+/// As with the file source, each object is named after the tag of its release image (e.g.
+/// `releases/1.0.0.json`), which is combined with the given registry and repository to form the
+/// payload of the release.
+pub fn fetch_releases(
+    region: Region,
+    bucket: &str,
+    prefix: &str,
+    registry: &str,
+    repo: &str,
+) -> Result<Vec<Release>, Error> {
+    let client = S3Client::new(region);
+
+    let mut releases = Vec::new();
+    for key in list_objects(&client, bucket, prefix)? {
+        let tag = match key.rsplit('/').next() {
+            Some(name) if name.ends_with(".json") => name.trim_right_matches(".json").to_string(),
+            _ => {
+                debug!("skipping s3://{}/{}", bucket, key);
+                continue;
+            }
+        };
+
+        trace!("reading metadata from s3://{}/{}", bucket, key);
+        let body = client
+            .get_object(GetObjectRequest {
+                bucket: bucket.to_string(),
+                key: key.clone(),
+                ..Default::default()
+            })
+            .sync()
+            .context(format!("failed to fetch s3://{}/{}", bucket, key))?
+            .body
+            .ok_or_else(|| format_err!("s3://{}/{} has no content", bucket, key))?
+            .concat2()
+            .wait()?;
+
+        releases.push(Release {
+            source: registry::payload(registry, repo, &tag),
+            arch: None,
+            created: None,
+            digest: None,
+            metadata: serde_json::from_slice(&body)
+                .context(format!("failed to parse s3://{}/{}", bucket, key))?,
+        });
+    }
+    Ok(releases)
+}
+
+fn list_objects(client: &S3Client, bucket: &str, prefix: &str) -> Result<Vec<String>, Error> {
+    let mut keys = Vec::new();
+    let mut token = None;
+    loop {
+        let output = client
+            .list_objects_v2(ListObjectsV2Request {
+                bucket: bucket.to_string(),
+                prefix: Some(prefix.to_string()),
+                continuation_token: token,
+                ..Default::default()
+            })
+            .sync()
+            .context(format!("failed to list objects in s3://{}/{}", bucket, prefix))?;
+
+        keys.extend(
+            output
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key),
+        );
+
+        match (output.is_truncated, output.next_continuation_token) {
+            (Some(true), Some(next)) => token = Some(next),
+            _ => break,
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
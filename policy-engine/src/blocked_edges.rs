@@ -0,0 +1,206 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Updates blocked by a remote list, which is a JSON document of the following form:
+//!
+//! `[{"from": "4\\.1\\..*", "to": "4\\.2\\.0", "reason": "..."}, ...]`
+//!
+//! Each pattern is a regular expression which must match the whole version on that end of the
+//! update.
+
+use cincinnati::Graph;
+use failure::{Error, ResultExt};
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use regex::Regex;
+use serde_json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub struct Rule {
+    from: Regex,
+    to: Regex,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct Document {
+    from: String,
+    to: String,
+    reason: String,
+}
+
+/// Remote list of blocked updates, which is fetched again once the period has elapsed. If the
+/// list can't be fetched, the last list which was fetched successfully continues to be used. Only
+/// one request fetches the list at a time; the others are answered with the previous list until
+/// the new one arrives.
+#[derive(Clone)]
+pub struct BlockedEdges {
+    url: Uri,
+    period: Duration,
+    connector: HttpsConnector<HttpConnector>,
+    rules: Arc<RwLock<Option<(Instant, Arc<Vec<Rule>>)>>>,
+    refreshing: Arc<AtomicBool>,
+}
+
+/// Marks the list as being fetched, until it is dropped along with the request which fetches it.
+struct Refresh(Arc<AtomicBool>);
+
+impl Drop for Refresh {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl BlockedEdges {
+    pub fn new(url: Uri, period: Duration) -> Result<BlockedEdges, Error> {
+        Ok(BlockedEdges {
+            url,
+            period,
+            connector: HttpsConnector::new(1).context("failed to initialize TLS")?,
+            rules: Arc::new(RwLock::new(None)),
+            refreshing: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn rules(&self) -> Box<Future<Item = Arc<Vec<Rule>>, Error = Error>> {
+        let cached = self.rules
+            .read()
+            .expect("rules lock has been poisoned")
+            .clone();
+        let refresh = match cached {
+            Some((fetched, ref rules)) => {
+                if fetched.elapsed() < self.period || self.refreshing.swap(true, Ordering::SeqCst)
+                {
+                    return Box::new(future::ok(rules.clone()));
+                }
+                Some(Refresh(self.refreshing.clone()))
+            }
+            // Until a list has been fetched, there's no list to answer the other requests with.
+            None => None,
+        };
+        let cache = self.rules.clone();
+        Box::new(
+            Client::builder()
+                .build::<_, Body>(self.connector.clone())
+                .get(self.url.clone())
+                .from_err::<Error>()
+                .and_then(|res| {
+                    if res.status().is_success() {
+                        future::ok(res)
+                    } else {
+                        future::err(format_err!(
+                            "failed to fetch blocked edges: {}",
+                            res.status()
+                        ))
+                    }
+                })
+                .and_then(|res| res.into_body().concat2().from_err::<Error>())
+                .and_then(|body| parse(&body))
+                .then(move |result| {
+                    let _refresh = refresh;
+                    match (result, cached) {
+                        (Ok(rules), _) => {
+                            let rules = Arc::new(rules);
+                            *cache.write().expect("rules lock has been poisoned") =
+                                Some((Instant::now(), rules.clone()));
+                            Ok(rules)
+                        }
+                        (Err(err), Some((_, rules))) => {
+                            warn!("Using previously fetched blocked edges: {}", err);
+                            Ok(rules)
+                        }
+                        (Err(err), None) => Err(err),
+                    }
+                }),
+        )
+    }
+}
+
+fn parse(body: &[u8]) -> Result<Vec<Rule>, Error> {
+    let documents: Vec<Document> =
+        serde_json::from_slice(body).context("failed to parse blocked edges")?;
+    documents
+        .into_iter()
+        .map(|document| {
+            Ok(Rule {
+                from: Regex::new(&format!("^(?:{})$", document.from))?,
+                to: Regex::new(&format!("^(?:{})$", document.to))?,
+                reason: document.reason,
+            })
+        })
+        .collect()
+}
+
+/// Removes the updates matching any of the given rules.
+pub fn remove(graph: &mut Graph, rules: &[Rule]) -> Result<(), Error> {
+    let blocked: Vec<_> = graph
+        .transitions()
+        .filter_map(|(source, target)| {
+            let from = graph.release(&source)?.version().to_string();
+            let to = graph.release(&target)?.version().to_string();
+            let rule = rules
+                .iter()
+                .find(|rule| rule.from.is_match(&from) && rule.to.is_match(&to))?;
+            debug!("Blocking update from {} to {}: {}", from, to, rule.reason);
+            Some((source, target))
+        })
+        .collect();
+
+    for (source, target) in blocked {
+        graph.remove_transition(&source, &target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn remove_matching_edges() {
+        let versions = &["4.1.0", "4.1.1", "4.1.10", "4.2.0"];
+        let mut graph = testing::graph(
+            versions,
+            &[("4.1.0", "4.2.0"), ("4.1.1", "4.2.0"), ("4.1.10", "4.2.0")],
+        );
+        let rules = parse(br#"[{"from": "4\\.1\\.1", "to": "4\\.2\\..*", "reason": "bug"}]"#)
+            .unwrap();
+        remove(&mut graph, &rules).unwrap();
+        testing::assert_graph(&graph, versions, &[("4.1.0", "4.2.0"), ("4.1.10", "4.2.0")]);
+    }
+
+    #[test]
+    fn serve_stale_rules_while_refreshing() {
+        let blocked_edges =
+            BlockedEdges::new("http://localhost:0/".parse().unwrap(), Duration::from_secs(0))
+                .unwrap();
+        let rules = parse(br#"[{"from": "4\\.1\\.1", "to": "4\\.2\\..*", "reason": "bug"}]"#)
+            .unwrap();
+        *blocked_edges.rules.write().unwrap() = Some((Instant::now(), Arc::new(rules)));
+        blocked_edges.refreshing.store(true, Ordering::SeqCst);
+
+        assert_eq!(blocked_edges.rules().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        assert!(parse(br#"[{"from": "4\\.1\\.(", "to": "4\\.2\\.0", "reason": "bug"}]"#).is_err());
+        assert!(parse(br#"[{"from": "4\\.1\\.0", "to": "4\\.2\\.0"}]"#).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cincinnati::{Graph, Release};
+use failure::Error;
+use semver::Version;
+
+/// Metadata key under which the channels of a release are listed, separated by commas.
+pub const CHANNELS_KEY: &str = "io.openshift.upgrades.graph.release.channels";
+
+/// Checks the channels listed in the metadata of each release. A release may only belong to the
+/// known channels, and a channel named after a minor version (e.g. `stable-4.1`) may only contain
+/// releases up to and including that minor version. Invalid channels are either removed from the
+/// release's metadata, or the entire release is removed.
+pub struct Channels {
+    pub valid: Vec<String>,
+    pub key: String,
+    pub remove_releases: bool,
+}
+
+impl Channels {
+    pub fn apply(&self, graph: &mut Graph) -> Result<(), Error> {
+        let mut misconfigured = Vec::new();
+        for id in graph.release_ids().collect::<Vec<_>>() {
+            let version = match graph.release(&id) {
+                Some(Release::Concrete(release)) => release.version.clone(),
+                _ => continue,
+            };
+            let metadata = graph.metadata_mut(&id).expect("release is concrete");
+            let channels = match metadata.get(&self.key) {
+                Some(channels) => channels.clone(),
+                None => continue,
+            };
+
+            let (valid, invalid): (Vec<_>, Vec<_>) = channels
+                .split(',')
+                .map(str::trim)
+                .filter(|channel| !channel.is_empty())
+                .partition(|channel| self.is_valid(channel, &version));
+            if invalid.is_empty() {
+                continue;
+            }
+
+            warn!(
+                "Release {} lists invalid channels: {}",
+                version,
+                invalid.join(", ")
+            );
+            if self.remove_releases {
+                misconfigured.push(version);
+            } else {
+                metadata.insert(self.key.clone(), valid.join(","));
+            }
+        }
+
+        // Removing a release invalidates the IDs of the others, so they are looked up by version.
+        for version in misconfigured {
+            if let Some(id) = graph.find_by_version(&version) {
+                graph.remove_release(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_valid(&self, channel: &str, version: &Version) -> bool {
+        if !self.valid.iter().any(|valid| valid == channel) {
+            return false;
+        }
+
+        let minor = channel
+            .rsplit('-')
+            .next()
+            .and_then(|suffix| Version::parse(&format!("{}.0", suffix)).ok());
+        match minor {
+            Some(minor) => (version.major, version.minor) <= (minor.major, minor.minor),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    fn graph() -> Graph {
+        let mut graph = testing::graph(
+            &["4.1.0", "4.1.1", "4.2.0"],
+            &[("4.1.0", "4.1.1"), ("4.1.1", "4.2.0")],
+        );
+        testing::set_metadata(&mut graph, "4.1.0", "channels", "stable-4.1,fast");
+        testing::set_metadata(&mut graph, "4.1.1", "channels", "stable-4.1");
+        testing::set_metadata(&mut graph, "4.2.0", "channels", "stable-4.1, stable-4.2");
+        graph
+    }
+
+    fn channels(remove_releases: bool) -> Channels {
+        Channels {
+            valid: vec!["stable-4.1".to_string(), "stable-4.2".to_string()],
+            key: "channels".to_string(),
+            remove_releases,
+        }
+    }
+
+    #[test]
+    fn remove_invalid_channels() {
+        let mut graph = graph();
+        channels(false).apply(&mut graph).unwrap();
+        testing::assert_graph(
+            &graph,
+            &["4.1.0", "4.1.1", "4.2.0"],
+            &[("4.1.0", "4.1.1"), ("4.1.1", "4.2.0")],
+        );
+        assert_eq!(testing::metadata(&graph, "4.1.0"), [("channels", "stable-4.1")]);
+        assert_eq!(testing::metadata(&graph, "4.1.1"), [("channels", "stable-4.1")]);
+        assert_eq!(testing::metadata(&graph, "4.2.0"), [("channels", "stable-4.2")]);
+    }
+
+    #[test]
+    fn remove_misconfigured_releases() {
+        let mut graph = graph();
+        channels(true).apply(&mut graph).unwrap();
+        testing::assert_graph(&graph, &["4.1.1"], &[]);
+        assert_eq!(testing::metadata(&graph, "4.1.1"), [("channels", "stable-4.1")]);
+    }
+}
@@ -12,8 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use channels;
+use dead_ends::DeadEnds;
 use hyper::Uri;
+use rollout::Rollout;
+use semver::Version;
 use std::net::IpAddr;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
@@ -25,6 +32,67 @@ pub struct Options {
     #[structopt(long = "upstream", default_value = "http://localhost:8080/graph")]
     pub upstream: Uri,
 
+    /// Add updates from each release to all later releases with the same major and minor version
+    #[structopt(long = "zstream-edges")]
+    pub zstream_edges: bool,
+
+    /// Release excluded from the generated z-stream updates (may be repeated)
+    #[structopt(long = "zstream-exception")]
+    pub zstream_exceptions: Vec<Version>,
+
+    /// URL of a JSON list of blocked updates, given as patterns matching the versions on each end
+    #[structopt(long = "blocked-edges-url")]
+    pub blocked_edges_url: Option<Uri>,
+
+    /// Duration (in seconds) after which the list of blocked updates is fetched again
+    #[structopt(
+        long = "blocked-edges-period",
+        default_value = "300",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub blocked_edges_period: Duration,
+
+    /// Treatment of releases without updates, other than the newest (annotate or remove)
+    #[structopt(long = "dead-ends")]
+    pub dead_ends: Option<DeadEnds>,
+
+    /// Gradual rollout of a release, as <version>=<percentage> of clients (may be repeated);
+    /// clients without an ID are only offered releases which are rolled out to 100%
+    #[structopt(long = "rollout")]
+    pub rollouts: Vec<Rollout>,
+
+    /// Channel to which releases may belong; other channels are rejected (may be repeated)
+    #[structopt(long = "valid-channel")]
+    pub valid_channels: Vec<String>,
+
+    /// Metadata key listing the (comma-separated) channels of each release
+    #[structopt(long = "channel-key", raw(default_value = "channels::CHANNELS_KEY"))]
+    pub channel_key: String,
+
+    /// Remove releases listing invalid channels, rather than only the invalid channels
+    #[structopt(long = "remove-misconfigured-releases")]
+    pub remove_misconfigured_releases: bool,
+
+    /// Prefix of the metadata keys removed from releases before they are served (may be repeated)
+    #[structopt(long = "strip-metadata-prefix")]
+    pub strip_metadata_prefixes: Vec<String>,
+
+    /// URL of an external policy, to which the graph is posted and which returns the graph to serve
+    #[structopt(long = "webhook")]
+    pub webhook: Option<Uri>,
+
+    /// Duration (in seconds) after which the policy webhook is abandoned
+    #[structopt(
+        long = "webhook-timeout",
+        default_value = "5",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub webhook_timeout: Duration,
+
+    /// Serve the unmodified graph when the policy webhook fails, rather than failing the request
+    #[structopt(long = "webhook-fail-open")]
+    pub webhook_fail_open: bool,
+
     /// Address on which the server will listen
     #[structopt(long = "address", default_value = "127.0.0.1")]
     pub address: IpAddr,
@@ -33,3 +101,7 @@ pub struct Options {
     #[structopt(long = "port", default_value = "8081")]
     pub port: u16,
 }
+
+fn parse_duration(src: &str) -> Result<Duration, ParseIntError> {
+    Ok(Duration::from_secs(u64::from_str(src)?))
+}
@@ -0,0 +1,95 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cincinnati::Graph;
+use failure::Error;
+use std::str::FromStr;
+
+/// Metadata key with which releases without any updates are annotated.
+pub const DEAD_END_KEY: &str = "io.openshift.upgrades.graph.release.dead-end";
+
+/// Treatment of the releases, other than the newest, from which no updates are available.
+#[derive(Clone, Copy, Debug)]
+pub enum DeadEnds {
+    Annotate,
+    Remove,
+}
+
+impl FromStr for DeadEnds {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<DeadEnds, Error> {
+        match src {
+            "annotate" => Ok(DeadEnds::Annotate),
+            "remove" => Ok(DeadEnds::Remove),
+            _ => bail!("unknown dead end treatment: {}", src),
+        }
+    }
+}
+
+impl DeadEnds {
+    pub fn apply(self, graph: &mut Graph) -> Result<(), Error> {
+        let dead_ends = graph.dead_ends();
+        match self {
+            DeadEnds::Annotate => for id in dead_ends {
+                if let Some(metadata) = graph.metadata_mut(&id) {
+                    metadata.insert(DEAD_END_KEY.to_string(), "true".to_string());
+                }
+            },
+            DeadEnds::Remove => {
+                // Removing a release invalidates the IDs of the others, so they are looked up again
+                // by version.
+                let versions: Vec<_> = dead_ends
+                    .iter()
+                    .filter_map(|id| graph.release(id))
+                    .map(|release| release.version().clone())
+                    .collect();
+                for version in versions {
+                    if let Some(id) = graph.find_by_version(&version) {
+                        debug!("Removing dead end {}", version);
+                        graph.remove_release(id)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    fn graph() -> Graph {
+        testing::graph(&["1.0.0", "1.1.0", "2.0.0"], &[("1.0.0", "2.0.0")])
+    }
+
+    #[test]
+    fn annotate_dead_ends() {
+        let mut graph = graph();
+        DeadEnds::Annotate.apply(&mut graph).unwrap();
+        testing::assert_graph(&graph, &["1.0.0", "1.1.0", "2.0.0"], &[("1.0.0", "2.0.0")]);
+        assert_eq!(testing::metadata(&graph, "1.0.0"), []);
+        assert_eq!(testing::metadata(&graph, "1.1.0"), [(DEAD_END_KEY, "true")]);
+        assert_eq!(testing::metadata(&graph, "2.0.0"), []);
+    }
+
+    #[test]
+    fn remove_dead_ends() {
+        let mut graph = graph();
+        DeadEnds::Remove.apply(&mut graph).unwrap();
+        testing::assert_graph(&graph, &["1.0.0", "2.0.0"], &[("1.0.0", "2.0.0")]);
+    }
+}
@@ -14,13 +14,25 @@
 
 use actix_web::http::header::{self, HeaderValue};
 use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use blocked_edges::{self, BlockedEdges, Rule};
+use channels::Channels;
 use cincinnati::{CONTENT_TYPE_GRAPH_V1, Graph};
+use dead_ends::DeadEnds;
 use failure::Error;
 use futures::{future, Future, Stream};
 use hyper::{Body, Client, Request, Uri};
+use metadata;
+use rollout::{self, Rollout};
+use semver::Version;
 use serde_json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use webhook::Webhook;
+use zstream;
 
 pub fn index(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let state = req.state().clone();
+    let parameters = req.query().clone();
     match req.headers().get(header::ACCEPT) {
         Some(entry) if entry == HeaderValue::from_static(CONTENT_TYPE_GRAPH_V1) => Box::new(
             Client::new()
@@ -45,8 +57,9 @@ pub fn index(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error =
                     }
                 })
                 .and_then(|res| res.into_body().concat2().from_err::<Error>())
-                .and_then(|body| {
-                    let graph: Graph = serde_json::from_slice(&body)?;
+                .and_then(|body| Ok(serde_json::from_slice::<Graph>(&body)?))
+                .and_then(move |graph| state.apply(graph, parameters))
+                .and_then(|graph| {
                     Ok(HttpResponse::Ok()
                         .content_type(CONTENT_TYPE_GRAPH_V1)
                         .body(serde_json::to_string(&graph)?))
@@ -59,4 +72,66 @@ pub fn index(req: HttpRequest<State>) -> Box<Future<Item = HttpResponse, Error =
 #[derive(Clone)]
 pub struct State {
     pub upstream: Uri,
+    pub zstream_edges: bool,
+    pub zstream_exceptions: Vec<Version>,
+    pub blocked_edges: Option<BlockedEdges>,
+    pub channels: Option<Arc<Channels>>,
+    pub dead_ends: Option<DeadEnds>,
+    pub rollouts: Vec<Rollout>,
+    pub strip_metadata_prefixes: Vec<String>,
+    pub webhook: Option<Webhook>,
+}
+
+impl State {
+    /// Applies the configured policies to the upstream graph. The local policies are applied
+    /// first, so that the webhook (if any) is sent the resulting graph.
+    fn apply(
+        &self,
+        mut graph: Graph,
+        parameters: HashMap<String, String>,
+    ) -> Box<Future<Item = Graph, Error = Error>> {
+        let rules: Box<Future<Item = Arc<Vec<Rule>>, Error = Error>> = match self.blocked_edges {
+            Some(ref blocked_edges) => blocked_edges.rules(),
+            None => Box::new(future::ok(Arc::new(Vec::new()))),
+        };
+
+        let state = self.clone();
+        Box::new(
+            rules
+                .and_then(move |rules| {
+                    state
+                        .transform(&mut graph, &rules, &parameters)
+                        .map(|_| (state, graph, parameters))
+                })
+                .and_then(|(state, graph, parameters)| match state.webhook {
+                    Some(ref webhook) => webhook.apply(graph, parameters),
+                    None => Box::new(future::ok(graph)),
+                }),
+        )
+    }
+
+    fn transform(
+        &self,
+        graph: &mut Graph,
+        blocked_edges: &[Rule],
+        parameters: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        if self.zstream_edges {
+            zstream::add_edges(graph, &self.zstream_exceptions)?;
+        }
+        blocked_edges::remove(graph, blocked_edges)?;
+        if let Some(ref channels) = self.channels {
+            channels.apply(graph)?;
+        }
+        if let Some(dead_ends) = self.dead_ends {
+            dead_ends.apply(graph)?;
+        }
+        rollout::apply(
+            graph,
+            &self.rollouts,
+            parameters.get("id").map(String::as_str),
+        )?;
+        metadata::strip(graph, &self.strip_metadata_prefixes);
+        Ok(())
+    }
 }
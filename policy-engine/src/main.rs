@@ -19,18 +19,35 @@ extern crate env_logger;
 extern crate failure;
 extern crate futures;
 extern crate hyper;
+extern crate hyper_tls;
+#[macro_use]
 extern crate log;
+extern crate regex;
 extern crate semver;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate structopt;
+extern crate tokio_timer;
 
+mod blocked_edges;
+mod channels;
 mod config;
+mod dead_ends;
 mod graph;
+mod metadata;
+mod rollout;
+#[cfg(test)]
+mod testing;
+mod webhook;
+mod zstream;
 
 use actix_web::{http::Method, middleware::Logger, server, App};
 use failure::Error;
 use log::LevelFilter;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 fn main() -> Result<(), Error> {
@@ -48,8 +65,40 @@ fn main() -> Result<(), Error> {
         )
         .init();
 
+    let webhook = match opts.webhook {
+        Some(ref url) => Some(webhook::Webhook {
+            url: url.clone(),
+            timeout: opts.webhook_timeout,
+            fail_open: opts.webhook_fail_open,
+        }),
+        None => None,
+    };
+    let blocked_edges = match opts.blocked_edges_url {
+        Some(ref url) => Some(blocked_edges::BlockedEdges::new(
+            url.clone(),
+            opts.blocked_edges_period,
+        )?),
+        None => None,
+    };
+    let channels = if opts.valid_channels.is_empty() {
+        None
+    } else {
+        Some(Arc::new(channels::Channels {
+            valid: opts.valid_channels,
+            key: opts.channel_key,
+            remove_releases: opts.remove_misconfigured_releases,
+        }))
+    };
     let state = graph::State {
         upstream: opts.upstream,
+        zstream_edges: opts.zstream_edges,
+        zstream_exceptions: opts.zstream_exceptions,
+        blocked_edges,
+        channels,
+        dead_ends: opts.dead_ends,
+        rollouts: opts.rollouts,
+        strip_metadata_prefixes: opts.strip_metadata_prefixes,
+        webhook,
     };
     server::new(move || {
         App::with_state(state.clone())
@@ -0,0 +1,47 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cincinnati::Graph;
+
+/// Removes the metadata keys starting with any of the given prefixes from every release, so that
+/// internal annotations aren't served to clients.
+pub fn strip(graph: &mut Graph, prefixes: &[String]) {
+    if prefixes.is_empty() {
+        return;
+    }
+
+    for id in graph.release_ids().collect::<Vec<_>>() {
+        if let Some(metadata) = graph.metadata_mut(&id) {
+            metadata.retain(|key, _| !prefixes.iter().any(|prefix| key.starts_with(prefix)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn strip_prefixes() {
+        let mut graph = testing::graph(&["1.0.0", "2.0.0"], &[("1.0.0", "2.0.0")]);
+        testing::set_metadata(&mut graph, "1.0.0", "internal.build", "42");
+        testing::set_metadata(&mut graph, "1.0.0", "internal.owner", "team");
+        testing::set_metadata(&mut graph, "1.0.0", "url", "https://example.com");
+        strip(&mut graph, &["internal.".to_string()]);
+        testing::assert_graph(&graph, &["1.0.0", "2.0.0"], &[("1.0.0", "2.0.0")]);
+        assert_eq!(testing::metadata(&graph, "1.0.0"), [("url", "https://example.com")]);
+        assert_eq!(testing::metadata(&graph, "2.0.0"), []);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cincinnati::Graph;
+use failure::{Error, ResultExt};
+use semver::Version;
+use std::str::FromStr;
+
+/// Gradual rollout of a release, which is only offered as an update to the given percentage of
+/// clients. Clients are identified by the `id` request parameter. Clients without one are only
+/// offered the release once it has been rolled out to all clients.
+#[derive(Clone, Debug)]
+pub struct Rollout {
+    version: Version,
+    percentage: u8,
+}
+
+impl FromStr for Rollout {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<Rollout, Error> {
+        let mut parts = src.splitn(2, '=');
+        let version = parts.next().unwrap_or_default();
+        let percentage = parts
+            .next()
+            .ok_or_else(|| format_err!("rollout must be of the form <version>=<percentage>"))?;
+
+        let version = Version::parse(version).context("invalid rollout version")?;
+        let percentage = u8::from_str(percentage).context("invalid rollout percentage")?;
+        ensure!(percentage <= 100, "rollout percentage exceeds 100");
+        Ok(Rollout {
+            version,
+            percentage,
+        })
+    }
+}
+
+impl Rollout {
+    /// Checks whether the given client is part of the rollout. Each client is consistently either
+    /// in or out of the rollout of a given release, and clients are spread independently across
+    /// the rollouts of different releases.
+    fn includes(&self, client: &str) -> bool {
+        let hash = fnv1a(format!("{}/{}", client, self.version).as_bytes());
+        hash % 100 < u64::from(self.percentage)
+    }
+}
+
+/// Removes the updates to each release whose rollout doesn't include the given client.
+pub fn apply(graph: &mut Graph, rollouts: &[Rollout], client: Option<&str>) -> Result<(), Error> {
+    let excluded: Vec<_> = rollouts
+        .iter()
+        .filter(|rollout| match client {
+            _ if rollout.percentage >= 100 => false,
+            Some(client) => !rollout.includes(client),
+            None => true,
+        })
+        .filter_map(|rollout| graph.find_by_version(&rollout.version))
+        .collect();
+
+    let gated: Vec<_> = graph
+        .transitions()
+        .filter(|(_, target)| excluded.contains(target))
+        .collect();
+    for (source, target) in gated {
+        graph.remove_transition(&source, &target)?;
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash, which (unlike the standard library's hasher) is stable across releases, so
+/// that clients don't move in and out of rollouts when the policy engine is upgraded.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    const VERSIONS: &[&str] = &["1.0.0", "1.1.0", "1.2.0"];
+
+    fn graph() -> Graph {
+        testing::graph(
+            VERSIONS,
+            &[("1.0.0", "1.1.0"), ("1.0.0", "1.2.0"), ("1.1.0", "1.2.0")],
+        )
+    }
+
+    #[test]
+    fn parse_rollout() {
+        let rollout = Rollout::from_str("1.2.0=25").unwrap();
+        assert_eq!(rollout.version, Version::new(1, 2, 0));
+        assert_eq!(rollout.percentage, 25);
+
+        assert!(Rollout::from_str("1.2.0").is_err());
+        assert!(Rollout::from_str("1.2=25").is_err());
+        assert!(Rollout::from_str("1.2.0=101").is_err());
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn spread_clients() {
+        let rollout = Rollout::from_str("1.2.0=50").unwrap();
+        let included = (0..1000)
+            .filter(|client| rollout.includes(&client.to_string()))
+            .count();
+        assert!(included > 400 && included < 600, "{} clients included", included);
+    }
+
+    #[test]
+    fn gate_updates() {
+        let none = Rollout::from_str("1.2.0=0").unwrap();
+        let mut gated = graph();
+        apply(&mut gated, &[none], Some("client")).unwrap();
+        testing::assert_graph(&gated, VERSIONS, &[("1.0.0", "1.1.0")]);
+
+        let all = Rollout::from_str("1.2.0=100").unwrap();
+        let mut included = graph();
+        apply(&mut included, &[all.clone()], Some("client")).unwrap();
+        assert_eq!(included.transition_count(), 3);
+
+        let mut anonymous = graph();
+        apply(&mut anonymous, &[all], None).unwrap();
+        assert_eq!(anonymous.transition_count(), 3);
+    }
+
+    #[test]
+    fn gate_anonymous_clients() {
+        let half = Rollout::from_str("1.2.0=50").unwrap();
+        let mut anonymous = graph();
+        apply(&mut anonymous, &[half], None).unwrap();
+        testing::assert_graph(&anonymous, VERSIONS, &[("1.0.0", "1.1.0")]);
+    }
+}
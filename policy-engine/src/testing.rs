@@ -0,0 +1,90 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the tests of the policies, which describe graphs by the versions of their releases
+//! rather than by the indices of their nodes.
+
+use cincinnati::{ConcreteRelease, Graph, Release, ReleaseId};
+use semver::Version;
+use std::collections::{BTreeSet, HashMap};
+
+/// Builds a graph of releases with the given versions and updates. Each release's payload is
+/// named after its version, and none of them have metadata.
+pub fn graph(versions: &[&str], updates: &[(&str, &str)]) -> Graph {
+    let mut graph = Graph::default();
+    for version in versions {
+        graph
+            .add_release(Release::Concrete(ConcreteRelease {
+                version: Version::parse(version).unwrap(),
+                payload: format!("image/{}", version),
+                metadata: HashMap::new(),
+            }))
+            .unwrap();
+    }
+    for (from, to) in updates {
+        let source = id(&graph, from);
+        let target = id(&graph, to);
+        graph.add_transition(&source, &target).unwrap();
+    }
+    graph
+}
+
+fn id(graph: &Graph, version: &str) -> ReleaseId {
+    graph
+        .find_by_version(&Version::parse(version).unwrap())
+        .unwrap_or_else(|| panic!("release {} is missing", version))
+}
+
+/// Sets a metadata value of the release with the given version.
+pub fn set_metadata(graph: &mut Graph, version: &str, key: &str, value: &str) {
+    let id = id(graph, version);
+    graph
+        .metadata_mut(&id)
+        .unwrap()
+        .insert(key.to_string(), value.to_string());
+}
+
+/// Returns the metadata of the release with the given version, in order of the keys.
+pub fn metadata<'a>(graph: &'a Graph, version: &str) -> Vec<(&'a str, &'a str)> {
+    let mut metadata: Vec<_> = match graph.release(&id(graph, version)) {
+        Some(Release::Concrete(release)) => release
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    metadata.sort();
+    metadata
+}
+
+/// Checks that the graph holds exactly the releases with the given versions, and the given
+/// updates between them.
+pub fn assert_graph(graph: &Graph, versions: &[&str], updates: &[(&str, &str)]) {
+    let version = |id| graph.release(&id).unwrap().version().to_string();
+
+    let actual: BTreeSet<_> = graph.release_ids().map(&version).collect();
+    let expected: BTreeSet<_> = versions.iter().map(|version| version.to_string()).collect();
+    assert_eq!(actual, expected, "releases differ");
+
+    let actual: BTreeSet<_> = graph
+        .transitions()
+        .map(|(source, target)| (version(source), version(target)))
+        .collect();
+    let expected: BTreeSet<_> = updates
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+        .collect();
+    assert_eq!(actual, expected, "updates differ");
+}
@@ -0,0 +1,133 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::http::header::{self, HeaderValue};
+use cincinnati::Graph;
+use failure::Error;
+use futures::{future, Future, Stream};
+use hyper::{Body, Client, Request, Uri};
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_timer::Timeout;
+
+/// External policy, which is sent the graph along with the parameters of the client's request and
+/// responds with the graph to serve in its place. If the webhook fails (or doesn't respond in
+/// time), the request either fails or is served the unmodified graph, depending on whether the
+/// webhook fails open.
+#[derive(Clone)]
+pub struct Webhook {
+    pub url: Uri,
+    pub timeout: Duration,
+    pub fail_open: bool,
+}
+
+impl Webhook {
+    pub fn apply(
+        &self,
+        graph: Graph,
+        parameters: HashMap<String, String>,
+    ) -> Box<Future<Item = Graph, Error = Error> + Send> {
+        let body = match serde_json::to_vec(&json!({
+            "graph": graph,
+            "parameters": parameters,
+        })) {
+            Ok(body) => body,
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+
+        let request = Client::new()
+            .request(
+                Request::post(&self.url)
+                    .header(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    )
+                    .body(Body::from(body))
+                    .expect("unable to form request"),
+            )
+            .from_err::<Error>()
+            .and_then(|res| {
+                if res.status().is_success() {
+                    future::ok(res)
+                } else {
+                    future::err(format_err!("policy webhook failed: {}", res.status()))
+                }
+            })
+            .and_then(|res| res.into_body().concat2().from_err::<Error>())
+            .and_then(|body| Ok(serde_json::from_slice::<Graph>(&body)?));
+
+        let fail_open = self.fail_open;
+        Box::new(
+            Timeout::new(request, self.timeout)
+                .map_err(|err| match err.into_inner() {
+                    Some(err) => err,
+                    None => format_err!("policy webhook timed out"),
+                })
+                .then(move |result| match result {
+                    Err(ref err) if fail_open => {
+                        warn!("Serving the unmodified graph: {}", err);
+                        Ok(graph)
+                    }
+                    result => result,
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use testing;
+
+    /// Returns a webhook at an address which refuses connections.
+    fn unreachable_webhook(fail_open: bool) -> Webhook {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        Webhook {
+            url: format!("http://127.0.0.1:{}/", port).parse().unwrap(),
+            timeout: Duration::from_secs(5),
+            fail_open,
+        }
+    }
+
+    fn apply(webhook: &Webhook, graph: Graph) -> Result<Graph, Error> {
+        let (tx, rx) = mpsc::channel();
+        hyper::rt::run(webhook.apply(graph, HashMap::new()).then(move |result| {
+            tx.send(result).unwrap();
+            Ok(())
+        }));
+        rx.recv().unwrap()
+    }
+
+    fn graph() -> Graph {
+        testing::graph(&["1.0.0", "1.1.0"], &[("1.0.0", "1.1.0")])
+    }
+
+    #[test]
+    fn fail_open() {
+        let graph = apply(&unreachable_webhook(true), graph()).unwrap();
+        testing::assert_graph(&graph, &["1.0.0", "1.1.0"], &[("1.0.0", "1.1.0")]);
+    }
+
+    #[test]
+    fn fail_closed() {
+        assert!(apply(&unreachable_webhook(false), graph()).is_err());
+    }
+}
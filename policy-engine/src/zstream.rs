@@ -0,0 +1,80 @@
+// Copyright 2018 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cincinnati::Graph;
+use failure::Error;
+use semver::Version;
+use std::collections::{HashMap, HashSet};
+
+/// Adds an update from every release to each of the later releases with the same major and minor
+/// version (e.g. 4.1.0 -> 4.1.3), unless either of them is one of the given exceptions.
+pub fn add_edges(graph: &mut Graph, exceptions: &[Version]) -> Result<(), Error> {
+    let mut streams = HashMap::new();
+    for id in graph.release_ids() {
+        let version = graph.release(&id).expect("release ID is valid").version();
+        if exceptions.contains(version) {
+            continue;
+        }
+        streams
+            .entry((version.major, version.minor))
+            .or_insert_with(Vec::new)
+            .push((version.clone(), id));
+    }
+
+    // A stream of n releases needs n(n-1)/2 updates, so they are all added at once rather than
+    // checking the graph for cycles after each one.
+    let existing: HashSet<_> = graph.transitions().collect();
+    let mut transitions = Vec::new();
+    for (_, mut releases) in streams {
+        releases.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (i, (_, source)) in releases.iter().enumerate() {
+            for (_, target) in &releases[i + 1..] {
+                if !existing.contains(&(*source, *target)) {
+                    transitions.push((*source, *target));
+                }
+            }
+        }
+    }
+    graph.add_transitions(transitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    const VERSIONS: &[&str] = &["4.1.0", "4.1.1", "4.1.2", "4.2.0"];
+
+    fn graph() -> Graph {
+        testing::graph(VERSIONS, &[("4.1.0", "4.1.1")])
+    }
+
+    #[test]
+    fn add_stream_edges() {
+        let mut graph = graph();
+        add_edges(&mut graph, &[]).unwrap();
+        testing::assert_graph(
+            &graph,
+            VERSIONS,
+            &[("4.1.0", "4.1.1"), ("4.1.0", "4.1.2"), ("4.1.1", "4.1.2")],
+        );
+    }
+
+    #[test]
+    fn skip_exceptions() {
+        let mut graph = graph();
+        add_edges(&mut graph, &[Version::new(4, 1, 1)]).unwrap();
+        testing::assert_graph(&graph, VERSIONS, &[("4.1.0", "4.1.1"), ("4.1.0", "4.1.2")]);
+    }
+}